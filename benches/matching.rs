@@ -0,0 +1,35 @@
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{fixture_zip, transloc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rit_gtfsrt::gtfs::Gtfs;
+use rit_gtfsrt::schedule::{Schedule, VehicleStatuses};
+
+fn fixture_schedule() -> Schedule {
+  common::schedule_with(
+    transloc::<VehicleStatuses>("vehicle_statuses.json"),
+    Default::default(),
+  )
+}
+
+fn parse_gtfs(c: &mut Criterion) {
+  let zip = fixture_zip();
+  c.bench_function("parse fixture gtfs", |b| {
+    b.iter(|| Gtfs::from_zip(black_box(zip.clone())).unwrap())
+  });
+}
+
+fn find_trip_id(c: &mut Criterion) {
+  let schedule = fixture_schedule();
+  c.bench_function("find_trip_id for every fixture arrival", |b| {
+    b.iter(|| {
+      for arrival in &schedule.arrivals {
+        black_box(schedule.find_trip_id(black_box(arrival)));
+      }
+    })
+  });
+}
+
+criterion_group!(benches, parse_gtfs, find_trip_id);
+criterion_main!(benches);
@@ -1,3 +1,4 @@
+use crate::config::Config;
 use crate::protobuf_route::request;
 use crate::protobuf_route::GenFeedError;
 use crate::traits::Translate;
@@ -27,18 +28,12 @@ struct Announcement {
   urgent: bool,
 }
 
-pub async fn get_alerts(agency_id: u64) -> Result<Vec<FeedEntity>, GenFeedError> {
-  let announcements = match request::<Announcements>(&format!(
-    "https://feeds.transloc.com/3/announcements?contents=true&agencies={agency_id}"
+pub async fn get_alerts(config: &Config, agency_id: u64) -> Result<Vec<FeedEntity>, GenFeedError> {
+  let announcements = request::<Announcements>(&format!(
+    "{}/announcements?contents=true&agencies={agency_id}",
+    config.transloc_url
   ))
-  .await
-  {
-    Ok(announcements) => announcements,
-    Err(err) => {
-      log::error!("Couldn't request announcements: {err}");
-      return Ok(vec![]);
-    }
-  };
+  .await?;
   Ok(
     announcements
       .announcements
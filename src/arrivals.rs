@@ -1,105 +1,249 @@
-use crate::protobuf_route::GenFeedError;
-use crate::schedule::{Schedule, get_arrival_time, day_time_serializer};
+use crate::poller::now;
+use crate::schedule::{trip_instance_id, ArrivalData, Schedule, Vehicle};
+use crate::tracker::VehicleTracker;
+use crate::uncertainty::PredictionHistory;
 use gtfs_rt::{
   trip_update::{stop_time_update::ScheduleRelationship, StopTimeEvent, StopTimeUpdate},
   vehicle_position::VehicleStopStatus,
-  FeedEntity, Position, TripUpdate, VehicleDescriptor, VehiclePosition,
+  FeedEntity, Position, TripDescriptor, TripUpdate, VehicleDescriptor, VehiclePosition,
 };
-use itertools::Itertools;
-use std::iter::{once, Iterator};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// A vehicle at a stop going slower than this (in mph) is stopped there
+const STOPPED_SPEED: f32 = 3.0;
+/// A vehicle this close (in metres) to its next stop is pulling in to it
+const INCOMING_DISTANCE: f64 = 200.0;
 
 fn mph_to_meters(mph: f32) -> f32 {
   mph * 0.44704
 }
 
-pub async fn get_trip_arrivals(schedule: &Schedule) -> Result<Vec<FeedEntity>, GenFeedError> {
-  Ok(
-    schedule
-      .arrivals
+/// The TransLoc stop a vehicle is at or headed to, and which of the two.
+fn stop_status(
+  schedule: &Schedule,
+  vehicle: &Vehicle,
+  next: Option<&ArrivalData>,
+) -> Option<(u64, VehicleStopStatus)> {
+  if let Some(stop_id) = vehicle.current_stop_id {
+    if vehicle.speed < STOPPED_SPEED {
+      return Some((stop_id, VehicleStopStatus::StoppedAt));
+    }
+  }
+  // Otherwise it's on its way to the next stop, even if it's still pulling
+  // out of the current one
+  let stop_id = vehicle
+    .next_stop
+    .or_else(|| next.map(|next| next.arrival.stop_id))?;
+  let distance = schedule
+    .arrivals
+    .iter()
+    .filter(|arrival| arrival.vehicle_id == vehicle.id && arrival.stop_id == stop_id)
+    .map(|arrival| arrival.distance)
+    .min_by(f64::total_cmp);
+  let status = match distance {
+    Some(distance) if distance <= INCOMING_DISTANCE => VehicleStopStatus::IncomingAt,
+    _ => VehicleStopStatus::InTransitTo,
+  };
+  Some((stop_id, status))
+}
+
+/// How sure we are that a vehicle is the one running a trip instance its
+/// arrivals `group` matched: the tracker has it on that trip, then it's the
+/// furthest along, then the lowest vehicle id wins. Higher is better.
+fn running_rank(tracker: &VehicleTracker, group: &[ArrivalData]) -> (bool, u32, Reverse<u64>) {
+  let first = &group[0];
+  let vehicle_id = first.arrival.vehicle_id;
+  let instance_id = trip_instance_id(&first.trip_descriptor);
+  let on_trip = tracker.get(vehicle_id).map_or(false, |assignment| {
+    trip_instance_id(&assignment.trip) == instance_id
+  });
+  (on_trip, first.stop_time.stop_sequence, Reverse(vehicle_id))
+}
+
+pub fn get_trip_arrivals(
+  schedule: &Schedule,
+  matched: Vec<ArrivalData>,
+  tracker: &VehicleTracker,
+  history: &PredictionHistory,
+) -> Vec<FeedEntity> {
+  let polled_at = now() as i64;
+  // Every prediction TransLoc has for a vehicle on one trip instance, in the
+  // order we first saw them
+  let mut groups: Vec<Vec<ArrivalData>> = vec![];
+  let mut group_index: HashMap<(u64, String), usize> = HashMap::new();
+  for arrival_data in matched {
+    let key = (
+      arrival_data.arrival.vehicle_id,
+      trip_instance_id(&arrival_data.trip_descriptor),
+    );
+    match group_index.get(&key) {
+      Some(&index) => groups[index].push(arrival_data),
+      None => {
+        group_index.insert(key, groups.len());
+        groups.push(vec![arrival_data]);
+      }
+    }
+  }
+
+  for group in &mut groups {
+    group.sort_by_key(|arrival_data| {
+      (
+        arrival_data.stop_time.stop_sequence,
+        arrival_data.arrival.timestamp,
+      )
+    });
+    group.dedup_by_key(|arrival_data| arrival_data.stop_time.stop_sequence);
+  }
+
+  // Bunched buses, or one running into its next trip, can be matched to the
+  // same trip instance, but only one of them is running it
+  let mut instances: Vec<String> = vec![];
+  let mut running: HashMap<String, &[ArrivalData]> = HashMap::new();
+  for group in &groups {
+    let instance_id = trip_instance_id(&group[0].trip_descriptor);
+    match running.get_mut(&instance_id) {
+      Some(current) => {
+        if running_rank(tracker, group) > running_rank(tracker, current) {
+          *current = group;
+        }
+      }
+      None => {
+        instances.push(instance_id.clone());
+        running.insert(instance_id, group);
+      }
+    }
+  }
+
+  let mut trip_updates: Vec<FeedEntity> = vec![];
+  for instance_id in &instances {
+    let group = running[instance_id];
+    let first = &group[0];
+    let vehicle_id = first.arrival.vehicle_id;
+    let vehicle = schedule.vehicles.get(&vehicle_id);
+    let stop_time_update = group
       .iter()
-      .filter_map(|arrival| {
-        schedule
-          .find_trip_id(arrival)
-      })
-      .flat_map(|arrival_data| {
-        println!("-----");
-        let arrival = arrival_data.arrival;
-        let (local_arrival_time_str, local_arrival_time) = get_arrival_time(&arrival);
-        let vehicle = schedule.vehicles.get(&arrival.vehicle_id);
+      .map(|arrival_data| {
+        let arrival = &arrival_data.arrival;
+        let local_arrival_time = arrival_data.service_time.secs;
         let delta = (local_arrival_time as i64 - arrival_data.scheduled_arrival as i64) as i32;
-        println!("Stop is {:?}", arrival_data.csv_stop);
-        println!("Delta: {delta}");
-        println!("{} / {:?} / {} vs scheduled: {:?}", arrival.timestamp, (local_arrival_time_str, local_arrival_time), day_time_serializer(local_arrival_time), (day_time_serializer(arrival_data.scheduled_arrival), arrival_data.scheduled_arrival));
-        let time = StopTimeEvent {
-          delay: Some(-delta),
-          uncertainty: Some(60),
-          //time: Some(arrival.timestamp),
-          time: None,
+        let delay = match &arrival_data.frequency {
+          // Without exact_times there's only a headway, so no schedule to be late against
+          Some(frequency) if frequency.exact_times == 0 => None,
+          _ => Some(delta),
         };
-        let vehicle_descriptor = vehicle.map(|vehicle| VehicleDescriptor {
-          id: Some(arrival.vehicle_id.to_string()),
-          label: Some(vehicle.call_name.clone()),
-          license_plate: None,
-        });
-        let trip = FeedEntity {
-          id: format!("{}-{}", arrival_data.stop_time.trip_id, arrival.timestamp),
-          is_deleted: None,
-          trip_update: Some(TripUpdate {
-            trip: arrival_data.trip_descriptor.clone(),
-            vehicle: vehicle_descriptor.clone(),
-            stop_time_update: vec![StopTimeUpdate {
-              stop_sequence: Some(arrival_data.stop_time.stop_sequence),
-              stop_id: Some(arrival_data.stop_time.stop_id.to_string()),
-              arrival: Some(time.clone()),
-              departure: Some(time),
-              schedule_relationship: Some(ScheduleRelationship::Scheduled.into()),
-            }],
-            timestamp: Some(vehicle.map_or_else(
-              || {
-                SystemTime::now()
-                  .duration_since(UNIX_EPOCH)
-                  .expect("Can't get time for trip")
-                  .as_secs()
-              },
-              |vehicle| vehicle.timestamp / 1000,
-            )),
-            delay: None,
-          }),
-          vehicle: None,
-          alert: None,
+        let time = StopTimeEvent {
+          delay,
+          uncertainty: Some(history.uncertainty(arrival, polled_at)),
+          time: Some(arrival.timestamp),
         };
-        if let Some(vehicle) = vehicle {
-          let vehicle = FeedEntity {
-            id: format!("vehicle-{}", vehicle.id),
-            is_deleted: None,
-            trip_update: None,
-            vehicle: Some(VehiclePosition {
-              trip: Some(arrival_data.trip_descriptor),
-              vehicle: vehicle_descriptor,
-              position: Some(Position {
-                latitude: vehicle.position.0,
-                longitude: vehicle.position.1,
-                bearing: Some(vehicle.heading),
-                odometer: None,
-                speed: Some(mph_to_meters(vehicle.speed)),
-              }),
-              current_stop_sequence: Some(arrival_data.stop_time.stop_sequence),
-              stop_id: Some(arrival_data.stop_time.stop_id.to_string()),
-              current_status: Some(VehicleStopStatus::InTransitTo.into()),
-              timestamp: Some(vehicle.timestamp / 1000),
-              congestion_level: None,
-              occupancy_status: None,
-            }),
-            alert: None,
-          };
-          Box::new(once(vehicle).chain(once(trip))) as Box<dyn Iterator<Item = FeedEntity>>
-        } else {
-          Box::new(once(trip)) as Box<dyn Iterator<Item = FeedEntity>>
+        StopTimeUpdate {
+          stop_sequence: Some(arrival_data.stop_time.stop_sequence),
+          stop_id: Some(arrival_data.stop_time.stop_id.to_string()),
+          arrival: Some(time.clone()),
+          departure: Some(time),
+          schedule_relationship: Some(ScheduleRelationship::Scheduled.into()),
         }
       })
-      .unique_by(|entity| entity.id.clone())
-      .filter(|entity| entity.trip_update.is_none())
-      .collect(),
-  )
+      .collect();
+    trip_updates.push(FeedEntity {
+      id: instance_id.clone(),
+      is_deleted: None,
+      trip_update: Some(TripUpdate {
+        trip: first.trip_descriptor.clone(),
+        vehicle: vehicle.map(vehicle_descriptor),
+        stop_time_update,
+        timestamp: Some(vehicle.map_or_else(now, |vehicle| vehicle.timestamp / 1000)),
+        delay: None,
+      }),
+      vehicle: None,
+      alert: None,
+    });
+  }
+
+  // Every vehicle gets a position, whether or not any of its arrivals matched.
+  // Only the vehicle running a trip instance is on it; the rest are just on
+  // their route.
+  let mut next_arrivals: HashMap<u64, &ArrivalData> = HashMap::new();
+  for arrival_data in instances
+    .iter()
+    .flat_map(|instance_id| running[instance_id])
+  {
+    let next = next_arrivals
+      .entry(arrival_data.arrival.vehicle_id)
+      .or_insert(arrival_data);
+    if arrival_data.arrival.timestamp < next.arrival.timestamp {
+      *next = arrival_data;
+    }
+  }
+  let mut vehicles: Vec<_> = schedule.vehicles.values().collect();
+  vehicles.sort_by_key(|vehicle| vehicle.id);
+  let vehicles: Vec<FeedEntity> = vehicles
+    .into_iter()
+    .map(|vehicle| vehicle_position(schedule, vehicle, next_arrivals.get(&vehicle.id).copied()))
+    .collect();
+  vehicles.into_iter().chain(trip_updates).collect()
+}
+
+fn vehicle_descriptor(vehicle: &Vehicle) -> VehicleDescriptor {
+  VehicleDescriptor {
+    id: Some(vehicle.id.to_string()),
+    label: Some(vehicle.call_name.clone()),
+    license_plate: None,
+  }
+}
+
+/// Where `vehicle` is, on the trip of its `next` matched arrival if it has
+/// one, or else just on its route.
+fn vehicle_position(
+  schedule: &Schedule,
+  vehicle: &Vehicle,
+  next: Option<&ArrivalData>,
+) -> FeedEntity {
+  let trip = match next {
+    Some(next) => Some(next.trip_descriptor.clone()),
+    None => schedule
+      .route_crosswalk
+      .get(vehicle.route_id)
+      .map(|route_match| TripDescriptor {
+        route_id: Some(route_match.route_id.to_string()),
+        ..Default::default()
+      }),
+  };
+  let status = stop_status(schedule, vehicle, next).and_then(|(stop_id, status)| {
+    let stop_match = schedule.stop_crosswalk.get(stop_id)?;
+    Some((stop_match.stop_id, status))
+  });
+  // Which visit to the stop it is, as far as the trip we matched goes
+  let stop_sequence = match (status, next) {
+    (Some((stop_id, _)), Some(next)) => schedule.visit_at_or_before(
+      next.stop_time.trip_id,
+      stop_id,
+      next.stop_time.stop_sequence,
+    ),
+    _ => None,
+  };
+  FeedEntity {
+    id: format!("vehicle-{}", vehicle.id),
+    is_deleted: None,
+    trip_update: None,
+    vehicle: Some(VehiclePosition {
+      trip,
+      vehicle: Some(vehicle_descriptor(vehicle)),
+      position: Some(Position {
+        latitude: vehicle.position.0,
+        longitude: vehicle.position.1,
+        bearing: Some(vehicle.heading),
+        odometer: None,
+        speed: Some(mph_to_meters(vehicle.speed)),
+      }),
+      current_stop_sequence: stop_sequence,
+      stop_id: status.map(|(stop_id, _)| stop_id.to_string()),
+      current_status: status.map(|(_, status)| status.into()),
+      timestamp: Some(vehicle.timestamp / 1000),
+      congestion_level: None,
+      occupancy_status: None,
+    }),
+    alert: None,
+  }
 }
@@ -0,0 +1,222 @@
+use crate::crosswalk_cli::CrosswalkArgs;
+use crate::gtfs::{Gtfs, GtfsSource};
+use chrono_tz::{America::New_York, Tz};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_TIMEZONE: Tz = New_York;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "GTFS-realtime bridge for TransLoc agencies")]
+pub struct Args {
+  /// TOML configuration file
+  #[arg(short, long, env = "RIT_GTFSRT_CONFIG")]
+  config: Option<PathBuf>,
+  /// Address to bind the HTTP server to
+  #[arg(long, env = "RIT_GTFSRT_LISTEN")]
+  listen: Option<String>,
+  /// Base URL of the TransLoc feeds API
+  #[arg(long, env = "RIT_GTFSRT_TRANSLOC_URL")]
+  transloc_url: Option<String>,
+  /// Where static GTFS zips live, as `<base>/<agency code>.zip`
+  #[arg(long, env = "RIT_GTFSRT_GTFS_BASE_URL")]
+  gtfs_base_url: Option<String>,
+  /// Timezone local service times are computed in, instead of the GTFS agency_timezone
+  #[arg(long, env = "RIT_GTFSRT_TIMEZONE")]
+  timezone: Option<Tz>,
+  /// Seconds between upstream refreshes
+  #[arg(long, env = "RIT_GTFSRT_POLL_INTERVAL")]
+  poll_interval: Option<u64>,
+  /// Local hour before which arrivals may still belong to the previous service day
+  #[arg(long, env = "RIT_GTFSRT_SERVICE_DAY_CUTOFF")]
+  service_day_cutoff: Option<u32>,
+  /// How much better the best trip match must score than the runner-up
+  #[arg(long, env = "RIT_GTFSRT_MATCH_AMBIGUITY")]
+  match_ambiguity: Option<f64>,
+  /// Metres a GTFS stop can be from a TransLoc stop and still be matched to it by position
+  #[arg(long, env = "RIT_GTFSRT_STOP_MATCH_DISTANCE")]
+  stop_match_distance: Option<f64>,
+  /// Run a one-off command instead of the server
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  Crosswalk(CrosswalkArgs),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub listen: String,
+  pub transloc_url: String,
+  pub gtfs_base_url: String,
+  pub timezone: Option<Tz>,
+  pub poll_interval: u64,
+  pub service_day_cutoff: u32,
+  /// Arrivals whose best and second-best trips score closer than this are
+  /// left unmatched rather than guessed
+  pub match_ambiguity: f64,
+  /// How far (in metres) the nearest GTFS stop can be from a TransLoc stop
+  /// whose code and name matched nothing
+  pub stop_match_distance: f64,
+  /// Look every agency up on TransLoc's agencies endpoint, even ones with an `id`
+  pub discover_agencies: bool,
+  pub agencies: Vec<AgencyConfig>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      listen: "0.0.0.0:6969".to_owned(),
+      transloc_url: "https://feeds.transloc.com/3".to_owned(),
+      gtfs_base_url: "https://api.transloc.com/gtfs".to_owned(),
+      timezone: None,
+      poll_interval: 15,
+      service_day_cutoff: 4,
+      match_ambiguity: 0.1,
+      stop_match_distance: 50.0,
+      discover_agencies: false,
+      agencies: vec![AgencyConfig {
+        id: Some(643),
+        code: "rit".to_owned(),
+        gtfs_url: None,
+        gtfs_path: None,
+        timezone: None,
+        poll_interval: None,
+        service_day_cutoff: None,
+        match_ambiguity: None,
+        stop_match_distance: None,
+        crosswalk: None,
+      }],
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AgencyConfig {
+  /// TransLoc's numeric id, discovered from the agency code when left out
+  pub id: Option<u64>,
+  pub code: String,
+  /// Static GTFS zip to download instead of `<gtfs_base_url>/<code>.zip`
+  pub gtfs_url: Option<String>,
+  /// Local static GTFS zip, which takes precedence over any URL
+  pub gtfs_path: Option<PathBuf>,
+  pub timezone: Option<Tz>,
+  pub poll_interval: Option<u64>,
+  pub service_day_cutoff: Option<u32>,
+  pub match_ambiguity: Option<f64>,
+  pub stop_match_distance: Option<f64>,
+  /// TOML file of hand-made TransLoc -> GTFS mappings
+  pub crosswalk: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io(io::Error, PathBuf),
+  Toml(toml::de::Error, PathBuf),
+}
+impl Error for ConfigError {}
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Io(err, path) => write!(f, "ConfigError(Io({err}, {}))", path.display()),
+      Self::Toml(err, path) => write!(f, "ConfigError(Toml({err}, {}))", path.display()),
+    }
+  }
+}
+
+impl Config {
+  /// Loads the config file named by `args` (if any), then applies the
+  /// environment/CLI overrides on top of it.
+  pub fn load(args: Args) -> Result<Config, ConfigError> {
+    let mut config = match &args.config {
+      Some(path) => {
+        let text = fs::read_to_string(path).map_err(|err| ConfigError::Io(err, path.clone()))?;
+        toml::from_str(&text).map_err(|err| ConfigError::Toml(err, path.clone()))?
+      }
+      None => Config::default(),
+    };
+    if let Some(listen) = args.listen {
+      config.listen = listen;
+    }
+    if let Some(transloc_url) = args.transloc_url {
+      config.transloc_url = transloc_url;
+    }
+    if let Some(gtfs_base_url) = args.gtfs_base_url {
+      config.gtfs_base_url = gtfs_base_url;
+    }
+    if let Some(timezone) = args.timezone {
+      config.timezone = Some(timezone);
+    }
+    if let Some(poll_interval) = args.poll_interval {
+      config.poll_interval = poll_interval;
+    }
+    if let Some(service_day_cutoff) = args.service_day_cutoff {
+      config.service_day_cutoff = service_day_cutoff;
+    }
+    if let Some(match_ambiguity) = args.match_ambiguity {
+      config.match_ambiguity = match_ambiguity;
+    }
+    if let Some(stop_match_distance) = args.stop_match_distance {
+      config.stop_match_distance = stop_match_distance;
+    }
+    Ok(config)
+  }
+
+  pub fn gtfs_source(&self, agency: &AgencyConfig) -> GtfsSource {
+    if let Some(path) = &agency.gtfs_path {
+      return GtfsSource::Path(path.clone());
+    }
+    match &agency.gtfs_url {
+      Some(url) => GtfsSource::Url(url.clone()),
+      None => GtfsSource::Url(format!(
+        "{}/{}.zip",
+        self.gtfs_base_url.trim_end_matches('/'),
+        agency.code
+      )),
+    }
+  }
+
+  /// The timezone to compute local service times in: the config override if
+  /// there is one, otherwise the static feed's agency_timezone.
+  pub fn timezone(&self, agency: &AgencyConfig, gtfs: &Gtfs) -> Tz {
+    agency
+      .timezone
+      .or(self.timezone)
+      .or(gtfs.timezone)
+      .unwrap_or_else(|| {
+        log::warn!(
+          "No timezone for {} in config or agency.txt, assuming {DEFAULT_TIMEZONE}",
+          agency.code
+        );
+        DEFAULT_TIMEZONE
+      })
+  }
+
+  pub fn service_day_cutoff(&self, agency: &AgencyConfig) -> u32 {
+    agency.service_day_cutoff.unwrap_or(self.service_day_cutoff)
+  }
+
+  pub fn match_ambiguity(&self, agency: &AgencyConfig) -> f64 {
+    agency.match_ambiguity.unwrap_or(self.match_ambiguity)
+  }
+
+  pub fn stop_match_distance(&self, agency: &AgencyConfig) -> f64 {
+    agency
+      .stop_match_distance
+      .unwrap_or(self.stop_match_distance)
+  }
+
+  pub fn poll_interval(&self, agency: &AgencyConfig) -> Duration {
+    Duration::from_secs(agency.poll_interval.unwrap_or(self.poll_interval))
+  }
+}
@@ -0,0 +1,344 @@
+use crate::config::ConfigError;
+use crate::gtfs::{CSVStop, Gtfs};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Hand-maintained TransLoc -> GTFS mappings, from an agency's `crosswalk`
+/// file. They win over every automatic rule.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrosswalkFile {
+  /// TransLoc route id -> GTFS route_id
+  pub routes: BTreeMap<String, u64>,
+  /// TransLoc stop id -> GTFS stop_id
+  pub stops: BTreeMap<String, u64>,
+}
+
+impl CrosswalkFile {
+  pub fn load(path: &Path) -> Result<CrosswalkFile, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|err| ConfigError::Io(err, path.to_path_buf()))?;
+    toml::from_str(&text).map_err(|err| ConfigError::Toml(err, path.to_path_buf()))
+  }
+
+  fn route(&self, transloc_id: u64) -> Option<u64> {
+    self.routes.get(&transloc_id.to_string()).copied()
+  }
+
+  fn stop(&self, transloc_id: u64) -> Option<u64> {
+    self.stops.get(&transloc_id.to_string()).copied()
+  }
+}
+
+/// Which rule joined a TransLoc route to a GTFS one, in the order they're
+/// tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteRule {
+  Override,
+  Id,
+  ShortName,
+  LongName,
+}
+
+/// The TransLoc side of a route, as far as matching it goes
+#[derive(Debug, Clone, Copy)]
+pub struct TranslocRoute<'a> {
+  pub id: u64,
+  pub short_name: &'a str,
+  pub long_name: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RouteMatch {
+  pub route_id: u64,
+  pub rule: RouteRule,
+}
+
+/// Which rule joined a TransLoc stop to a GTFS one, in the order they're
+/// tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopRule {
+  Override,
+  Code,
+  Name,
+  Nearest,
+}
+
+/// The TransLoc side of a stop, as far as matching it goes
+#[derive(Debug, Clone, Copy)]
+pub struct TranslocStop<'a> {
+  pub id: u64,
+  pub code: &'a str,
+  pub name: &'a str,
+  pub position: [f64; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StopMatch {
+  pub stop_id: u64,
+  pub rule: StopRule,
+  /// Metres between the TransLoc and GTFS positions
+  pub distance: f64,
+}
+
+/// Every mapping we made and which rule made it, plus the TransLoc routes
+/// and stops we couldn't join to exactly one GTFS entity.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct CrosswalkReport {
+  pub routes: BTreeMap<u64, RouteMatch>,
+  pub stops: BTreeMap<u64, StopMatch>,
+  pub unmapped_routes: Vec<u64>,
+  /// TransLoc route id -> the GTFS route_ids it could be
+  pub ambiguous_routes: BTreeMap<u64, Vec<u64>>,
+  pub unmapped_stops: Vec<u64>,
+  /// TransLoc stop id -> the GTFS stop_ids it could be
+  pub ambiguous_stops: BTreeMap<u64, Vec<u64>>,
+}
+
+impl CrosswalkReport {
+  pub fn new(routes: &RouteCrosswalk, stops: &StopCrosswalk) -> CrosswalkReport {
+    CrosswalkReport {
+      routes: routes
+        .matches
+        .iter()
+        .map(|(id, route)| (*id, *route))
+        .collect(),
+      stops: stops
+        .matches
+        .iter()
+        .map(|(id, stop)| (*id, *stop))
+        .collect(),
+      unmapped_routes: routes.unmapped.clone(),
+      ambiguous_routes: routes.ambiguous.clone(),
+      unmapped_stops: stops.unmapped.clone(),
+      ambiguous_stops: stops.ambiguous.clone(),
+    }
+  }
+
+  pub fn has_problems(&self) -> bool {
+    !(self.unmapped_routes.is_empty()
+      && self.ambiguous_routes.is_empty()
+      && self.unmapped_stops.is_empty()
+      && self.ambiguous_stops.is_empty())
+  }
+
+  /// Whether `other` has the same problems, whatever it matched
+  pub fn same_problems(&self, other: &CrosswalkReport) -> bool {
+    self.unmapped_routes == other.unmapped_routes
+      && self.ambiguous_routes == other.ambiguous_routes
+      && self.unmapped_stops == other.unmapped_stops
+      && self.ambiguous_stops == other.ambiguous_stops
+  }
+}
+impl fmt::Display for CrosswalkReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "unmapped TransLoc routes {:?}, ambiguous TransLoc routes {:?}, unmapped TransLoc stops {:?}, ambiguous TransLoc stops {:?}",
+      self.unmapped_routes, self.ambiguous_routes, self.unmapped_stops, self.ambiguous_stops
+    )
+  }
+}
+
+/// Lowercase, with punctuation and runs of whitespace collapsed to one space,
+/// so "Park Point " and "Park-Point" agree.
+pub fn normalize_name(name: &str) -> String {
+  name
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(|word| word.to_lowercase())
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+#[derive(Debug, Default)]
+pub struct RouteCrosswalk {
+  matches: HashMap<u64, RouteMatch>,
+  unmapped: Vec<u64>,
+  ambiguous: BTreeMap<u64, Vec<u64>>,
+}
+
+impl RouteCrosswalk {
+  pub fn new<'a>(
+    routes: impl IntoIterator<Item = TranslocRoute<'a>>,
+    gtfs: &Gtfs,
+    overrides: &CrosswalkFile,
+  ) -> RouteCrosswalk {
+    let mut crosswalk = RouteCrosswalk::default();
+    for route in routes {
+      match match_route(&route, gtfs, overrides) {
+        Ok(route_match) => {
+          crosswalk.matches.insert(route.id, route_match);
+        }
+        Err(candidates) if candidates.is_empty() => crosswalk.unmapped.push(route.id),
+        Err(candidates) => {
+          crosswalk.ambiguous.insert(route.id, candidates);
+        }
+      }
+    }
+    crosswalk.unmapped.sort_unstable();
+    crosswalk
+  }
+
+  pub fn get(&self, transloc_id: u64) -> Option<RouteMatch> {
+    self.matches.get(&transloc_id).copied()
+  }
+}
+
+/// The GTFS route a TransLoc route is, or every GTFS route it could be if
+/// there isn't exactly one.
+fn match_route(
+  route: &TranslocRoute,
+  gtfs: &Gtfs,
+  overrides: &CrosswalkFile,
+) -> Result<RouteMatch, Vec<u64>> {
+  if let Some(route_id) = overrides.route(route.id) {
+    if gtfs.routes.contains_key(&route_id) {
+      return Ok(RouteMatch {
+        route_id,
+        rule: RouteRule::Override,
+      });
+    }
+    log::warn!(
+      "Crosswalk maps TransLoc route {} to GTFS route {route_id}, which doesn't exist",
+      route.id
+    );
+  }
+  if gtfs.routes.contains_key(&route.id) {
+    return Ok(RouteMatch {
+      route_id: route.id,
+      rule: RouteRule::Id,
+    });
+  }
+
+  let short_name = route.short_name.trim();
+  let long_name = normalize_name(route.long_name);
+  let mut ambiguous = vec![];
+  let rules = [
+    (RouteRule::ShortName, gtfs.routes_by_short_name(short_name)),
+    (RouteRule::LongName, gtfs.routes_by_long_name(&long_name)),
+  ];
+  for (rule, candidates) in rules {
+    match candidates.len() {
+      0 => continue,
+      1 => {
+        return Ok(RouteMatch {
+          route_id: candidates[0],
+          rule,
+        })
+      }
+      _ if ambiguous.is_empty() => ambiguous = candidates.to_vec(),
+      _ => {}
+    }
+  }
+  Err(ambiguous)
+}
+
+#[derive(Debug, Default)]
+pub struct StopCrosswalk {
+  matches: HashMap<u64, StopMatch>,
+  unmapped: Vec<u64>,
+  ambiguous: BTreeMap<u64, Vec<u64>>,
+}
+
+impl StopCrosswalk {
+  /// `max_distance` is how far, in metres, the nearest GTFS stop can be from
+  /// a TransLoc stop that matched nothing else.
+  pub fn new<'a>(
+    stops: impl IntoIterator<Item = TranslocStop<'a>>,
+    gtfs: &Gtfs,
+    overrides: &CrosswalkFile,
+    max_distance: f64,
+  ) -> StopCrosswalk {
+    let mut crosswalk = StopCrosswalk::default();
+    for stop in stops {
+      match match_stop(&stop, gtfs, overrides, max_distance) {
+        Ok(stop_match) => {
+          crosswalk.matches.insert(stop.id, stop_match);
+        }
+        Err(candidates) if candidates.is_empty() => crosswalk.unmapped.push(stop.id),
+        Err(candidates) => {
+          crosswalk.ambiguous.insert(stop.id, candidates);
+        }
+      }
+    }
+    crosswalk.unmapped.sort_unstable();
+    crosswalk
+  }
+
+  pub fn get(&self, transloc_id: u64) -> Option<StopMatch> {
+    self.matches.get(&transloc_id).copied()
+  }
+}
+
+/// Great-circle distance in metres between two `[lat, lon]`s
+pub fn distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+  const EARTH_RADIUS: f64 = 6_371_000.0;
+  let (lat_a, lat_b) = (a[0].to_radians(), b[0].to_radians());
+  let d_lat = lat_b - lat_a;
+  let d_lon = (b[1] - a[1]).to_radians();
+  let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+  2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// The GTFS stop a TransLoc stop is, or every GTFS stop it could be if there
+/// isn't exactly one.
+fn match_stop(
+  stop: &TranslocStop,
+  gtfs: &Gtfs,
+  overrides: &CrosswalkFile,
+  max_distance: f64,
+) -> Result<StopMatch, Vec<u64>> {
+  let stop_match = |csv_stop: &CSVStop, rule| StopMatch {
+    stop_id: csv_stop.stop_id,
+    rule,
+    distance: distance(stop.position, [csv_stop.stop_lat, csv_stop.stop_lon]),
+  };
+  if let Some(stop_id) = overrides.stop(stop.id) {
+    match gtfs.stops.get(&stop_id) {
+      Some(csv_stop) => return Ok(stop_match(csv_stop, StopRule::Override)),
+      None => log::warn!(
+        "Crosswalk maps TransLoc stop {} to GTFS stop {stop_id}, which doesn't exist",
+        stop.id
+      ),
+    }
+  }
+
+  let code = stop.code.trim();
+  let name = normalize_name(stop.name);
+  let csv_stops = |stop_ids: &[u64]| -> Vec<&CSVStop> {
+    stop_ids
+      .iter()
+      .filter_map(|stop_id| gtfs.stops.get(stop_id))
+      .collect()
+  };
+  let by_code = csv_stops(gtfs.stops_by_code(code));
+  let by_name = csv_stops(gtfs.stops_by_name(&name));
+  let mut ambiguous = vec![];
+  for (rule, candidates) in [(StopRule::Code, by_code), (StopRule::Name, by_name)] {
+    match candidates.as_slice() {
+      [] => continue,
+      [csv_stop] => return Ok(stop_match(csv_stop, rule)),
+      _ if ambiguous.is_empty() => {
+        ambiguous = candidates.iter().map(|csv_stop| csv_stop.stop_id).collect();
+      }
+      _ => {}
+    }
+  }
+
+  // A reused code or name usually means two stops in different places, so
+  // position can still settle it
+  let nearest = gtfs
+    .stops
+    .values()
+    // Stations and entrances aren't where buses stop
+    .filter(|csv_stop| csv_stop.location_type == 0)
+    .map(|csv_stop| stop_match(csv_stop, StopRule::Nearest))
+    .filter(|stop_match| stop_match.distance <= max_distance)
+    .min_by(|a, b| a.distance.total_cmp(&b.distance));
+  nearest.ok_or(ambiguous)
+}
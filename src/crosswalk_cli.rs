@@ -0,0 +1,284 @@
+use crate::config::Config;
+use crate::crosswalk::{
+  CrosswalkReport, RouteCrosswalk, StopCrosswalk, TranslocRoute, TranslocStop,
+};
+use crate::gtfs::{load_gtfs, Gtfs, GtfsSource};
+use crate::protobuf_route::{request, GenFeedError};
+use crate::registry::Registry;
+use crate::schedule::{RouteOutput, StopOutput};
+use clap::Args;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Compare TransLoc's stops and routes with the static GTFS and print a
+/// proposed crosswalk file
+#[derive(Args, Debug, Clone)]
+pub struct CrosswalkArgs {
+  /// Agency code, as configured
+  #[arg(long, default_value = "rit")]
+  agency: String,
+  /// Saved response of TransLoc's `/stops?include_routes=true`, instead of fetching it
+  #[arg(long)]
+  stops: Option<PathBuf>,
+  /// Saved response of TransLoc's `/routes`, instead of fetching it
+  #[arg(long)]
+  routes: Option<PathBuf>,
+  /// Static GTFS zip, instead of the agency's configured one
+  #[arg(long)]
+  gtfs: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum CrosswalkCliError {
+  UnknownAgency(String),
+  Feed(GenFeedError),
+  Io(io::Error, PathBuf),
+  Json(serde_path_to_error::Error<serde_json::Error>, PathBuf),
+}
+impl Error for CrosswalkCliError {}
+impl fmt::Display for CrosswalkCliError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::UnknownAgency(code) => write!(f, "CrosswalkCliError(UnknownAgency({code}))"),
+      Self::Feed(err) => write!(f, "CrosswalkCliError(Feed({err}))"),
+      Self::Io(err, path) => write!(f, "CrosswalkCliError(Io({err}, {}))", path.display()),
+      Self::Json(err, path) => write!(f, "CrosswalkCliError(Json({err}, {}))", path.display()),
+    }
+  }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, CrosswalkCliError> {
+  let text =
+    fs::read_to_string(path).map_err(|err| CrosswalkCliError::Io(err, path.to_path_buf()))?;
+  let jd = &mut serde_json::Deserializer::from_str(&text);
+  serde_path_to_error::deserialize(jd)
+    .map_err(|err| CrosswalkCliError::Json(err, path.to_path_buf()))
+}
+
+/// Reads `path` if we were given one, otherwise fetches `url`.
+async fn saved_or_fetched<T: DeserializeOwned>(
+  path: &Option<PathBuf>,
+  url: String,
+) -> Result<(T, String), CrosswalkCliError> {
+  match path {
+    Some(path) => Ok((read_json(path)?, path.display().to_string())),
+    None => Ok((request(&url).await.map_err(CrosswalkCliError::Feed)?, url)),
+  }
+}
+
+/// Prints a proposed crosswalk for one agency to stdout
+pub async fn run(
+  config: &Config,
+  registry: &Registry,
+  args: CrosswalkArgs,
+) -> Result<(), CrosswalkCliError> {
+  let agency = registry
+    .get(&args.agency)
+    .ok_or_else(|| CrosswalkCliError::UnknownAgency(args.agency.clone()))?;
+
+  let transloc_url = &config.transloc_url;
+  let (stops, stops_source): (StopOutput, _) = saved_or_fetched(
+    &args.stops,
+    format!(
+      "{transloc_url}/stops?include_routes=true&agencies={}",
+      agency.id
+    ),
+  )
+  .await?;
+  let (routes, routes_source): (RouteOutput, _) = saved_or_fetched(
+    &args.routes,
+    format!("{transloc_url}/routes?agencies={}", agency.id),
+  )
+  .await?;
+  let gtfs_source = match args.gtfs {
+    Some(path) => GtfsSource::Path(path),
+    None => config.gtfs_source(&agency.settings),
+  };
+  let loaded = load_gtfs(&gtfs_source, None)
+    .await
+    .map_err(CrosswalkCliError::Feed)?;
+
+  let routes: Vec<_> = routes.transloc_routes().collect();
+  let stops: Vec<_> = stops.transloc_stops().collect();
+  let route_crosswalk =
+    RouteCrosswalk::new(routes.iter().copied(), &loaded.gtfs, &agency.crosswalk);
+  let stop_crosswalk = StopCrosswalk::new(
+    stops.iter().copied(),
+    &loaded.gtfs,
+    &agency.crosswalk,
+    config.stop_match_distance(&agency.settings),
+  );
+  let report = CrosswalkReport::new(&route_crosswalk, &stop_crosswalk);
+
+  let mut out = format!(
+    "# Proposed crosswalk for {}, from {routes_source}, {stops_source} and {gtfs_source}.\n\
+     # Point the agency's `crosswalk` setting at this file to use it.\n",
+    args.agency
+  );
+  out.push_str(&proposal(&report, &routes, &stops, &loaded.gtfs));
+  print!("{out}");
+  Ok(())
+}
+
+/// Keeps a name from ending its comment line early
+fn comment(text: &str) -> String {
+  text.replace(['\n', '\r'], " ")
+}
+
+/// Every key of `matches` whose value is shared with another key
+fn conflicts(matches: impl Iterator<Item = (u64, u64)>) -> BTreeMap<u64, Vec<u64>> {
+  let mut targets: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+  for (transloc_id, gtfs_id) in matches {
+    targets.entry(gtfs_id).or_default().push(transloc_id);
+  }
+  targets.retain(|_, transloc_ids| transloc_ids.len() > 1);
+  targets
+}
+
+/// The `[routes]` and `[stops]` tables for everything in `report`, followed
+/// by its conflicts and whatever's left unmatched on either side as comments
+pub fn proposal(
+  report: &CrosswalkReport,
+  routes: &[TranslocRoute],
+  stops: &[TranslocStop],
+  gtfs: &Gtfs,
+) -> String {
+  let mut out = String::new();
+  write_proposal(&mut out, report, routes, stops, gtfs).expect("Writing to a String");
+  out
+}
+
+fn write_proposal(
+  out: &mut String,
+  report: &CrosswalkReport,
+  routes: &[TranslocRoute],
+  stops: &[TranslocStop],
+  gtfs: &Gtfs,
+) -> fmt::Result {
+  let route_names: BTreeMap<u64, &TranslocRoute> =
+    routes.iter().map(|route| (route.id, route)).collect();
+  let stop_names: BTreeMap<u64, &TranslocStop> = stops.iter().map(|stop| (stop.id, stop)).collect();
+
+  writeln!(out, "\n[routes]")?;
+  for (transloc_id, route_match) in &report.routes {
+    let csv_route = &gtfs.routes[&route_match.route_id];
+    writeln!(
+      out,
+      "{transloc_id} = {}  # {:?}: {} -> {}",
+      route_match.route_id,
+      route_match.rule,
+      comment(route_names[transloc_id].long_name),
+      comment(&csv_route.route_long_name),
+    )?;
+  }
+
+  writeln!(out, "\n[stops]")?;
+  for (transloc_id, stop_match) in &report.stops {
+    let csv_stop = &gtfs.stops[&stop_match.stop_id];
+    writeln!(
+      out,
+      "{transloc_id} = {}  # {:?}, {:.0}m: {} -> {}",
+      stop_match.stop_id,
+      stop_match.rule,
+      stop_match.distance,
+      comment(stop_names[transloc_id].name),
+      comment(&csv_stop.stop_name),
+    )?;
+  }
+
+  writeln!(out, "\n# Conflicts")?;
+  for (transloc_id, route_ids) in &report.ambiguous_routes {
+    writeln!(
+      out,
+      "# TransLoc route {transloc_id} ({}) could be GTFS routes {route_ids:?}",
+      comment(route_names[transloc_id].long_name)
+    )?;
+  }
+  for (transloc_id, stop_ids) in &report.ambiguous_stops {
+    writeln!(
+      out,
+      "# TransLoc stop {transloc_id} ({}) could be GTFS stops {stop_ids:?}",
+      comment(stop_names[transloc_id].name)
+    )?;
+  }
+  let shared_routes = report
+    .routes
+    .iter()
+    .map(|(transloc_id, route_match)| (*transloc_id, route_match.route_id));
+  for (route_id, transloc_ids) in conflicts(shared_routes) {
+    writeln!(
+      out,
+      "# GTFS route {route_id} is matched by TransLoc routes {transloc_ids:?}"
+    )?;
+  }
+  let shared_stops = report
+    .stops
+    .iter()
+    .map(|(transloc_id, stop_match)| (*transloc_id, stop_match.stop_id));
+  for (stop_id, transloc_ids) in conflicts(shared_stops) {
+    writeln!(
+      out,
+      "# GTFS stop {stop_id} is matched by TransLoc stops {transloc_ids:?}"
+    )?;
+  }
+
+  writeln!(out, "\n# Unmatched")?;
+  for transloc_id in &report.unmapped_routes {
+    let route = route_names[transloc_id];
+    writeln!(
+      out,
+      "# TransLoc route {transloc_id}: {} {}",
+      comment(route.short_name),
+      comment(route.long_name)
+    )?;
+  }
+  for transloc_id in &report.unmapped_stops {
+    let stop = stop_names[transloc_id];
+    writeln!(
+      out,
+      "# TransLoc stop {transloc_id}: {} {}",
+      comment(stop.code),
+      comment(stop.name)
+    )?;
+  }
+  let mapped_routes: Vec<u64> = report.routes.values().map(|route| route.route_id).collect();
+  let mut csv_routes: Vec<_> = gtfs
+    .routes
+    .values()
+    .filter(|csv_route| !mapped_routes.contains(&csv_route.route_id))
+    .collect();
+  csv_routes.sort_by_key(|csv_route| csv_route.route_id);
+  for csv_route in csv_routes {
+    writeln!(
+      out,
+      "# GTFS route {}: {} {}",
+      csv_route.route_id,
+      comment(&csv_route.route_short_name),
+      comment(&csv_route.route_long_name)
+    )?;
+  }
+  let mapped_stops: Vec<u64> = report.stops.values().map(|stop| stop.stop_id).collect();
+  // Stations and entrances aren't where buses stop, so aren't expected to match
+  let mut csv_stops: Vec<_> = gtfs
+    .stops
+    .values()
+    .filter(|csv_stop| csv_stop.location_type == 0 && !mapped_stops.contains(&csv_stop.stop_id))
+    .collect();
+  csv_stops.sort_by_key(|csv_stop| csv_stop.stop_id);
+  for csv_stop in csv_stops {
+    writeln!(
+      out,
+      "# GTFS stop {}: {} {}",
+      csv_stop.stop_id,
+      comment(&csv_stop.stop_code),
+      comment(&csv_stop.stop_name)
+    )?;
+  }
+  Ok(())
+}
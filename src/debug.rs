@@ -0,0 +1,127 @@
+use crate::crosswalk::{CrosswalkReport, RouteMatch, StopMatch};
+use crate::error::ApiError;
+use crate::gtfs::day_time_serializer;
+use crate::protobuf_route::{agency, snapshot};
+use crate::schedule::{
+  trip_instance_id, ArrivalData, MatchFailure, MatchReason, MatchTrace, Schedule,
+};
+use crate::State;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tide::{Body, Request, Response};
+
+/// A trip instance an arrival could have been, and how well it fit.
+#[derive(Serialize, Debug, Clone)]
+pub struct Candidate {
+  trip: String,
+  trip_id: u64,
+  stop_sequence: u32,
+  scheduled_arrival: String,
+  /// Lower is better
+  score: f64,
+}
+
+impl Candidate {
+  fn new(score: f64, data: &ArrivalData) -> Candidate {
+    Candidate {
+      trip: trip_instance_id(&data.trip_descriptor),
+      trip_id: data.stop_time.trip_id,
+      stop_sequence: data.stop_time.stop_sequence,
+      scheduled_arrival: day_time_serializer(data.scheduled_arrival),
+      score,
+    }
+  }
+}
+
+/// One TransLoc arrival, what it resolved to in the static GTFS, and which
+/// trip (if any) it was matched to.
+#[derive(Serialize, Debug, Clone)]
+pub struct ArrivalMatch {
+  vehicle_id: u64,
+  route_id: u64,
+  stop_id: u64,
+  timestamp: i64,
+  headsign: Option<String>,
+  transloc_trip_id: Option<u64>,
+  gtfs_route: Option<RouteMatch>,
+  gtfs_stop: Option<StopMatch>,
+  candidates: Vec<Candidate>,
+  chosen: Option<Candidate>,
+  reason: Option<MatchReason>,
+  failed_at: Option<MatchFailure>,
+}
+
+impl ArrivalMatch {
+  pub fn new(schedule: &Schedule, trace: &MatchTrace) -> ArrivalMatch {
+    let arrival = &trace.arrival;
+    let candidates: Vec<_> = trace
+      .candidates
+      .iter()
+      .map(|(score, data)| Candidate::new(*score, data))
+      .collect();
+    let (chosen, reason, failed_at) = match trace.outcome {
+      Ok((index, reason)) => (Some(candidates[index].clone()), Some(reason), None),
+      Err(failure) => (None, None, Some(failure)),
+    };
+    ArrivalMatch {
+      vehicle_id: arrival.vehicle_id,
+      route_id: arrival.route_id,
+      stop_id: arrival.stop_id,
+      timestamp: arrival.timestamp,
+      headsign: arrival.headsign.clone(),
+      transloc_trip_id: arrival.trip_id,
+      gtfs_route: schedule.route_crosswalk.get(arrival.route_id),
+      gtfs_stop: schedule.stop_crosswalk.get(arrival.stop_id),
+      candidates,
+      chosen,
+      reason,
+      failed_at,
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct Matches<'a> {
+  updated_at: DateTime<Utc>,
+  arrivals: &'a [ArrivalMatch],
+}
+
+fn matches_response(req: &Request<State>) -> Result<Response, ApiError> {
+  let agency = agency(req)?;
+  let snapshot = snapshot(req.state(), agency)?;
+  let matches = Matches {
+    updated_at: snapshot.updated_at.into(),
+    arrivals: &snapshot.matches,
+  };
+  let body = Body::from_json(&matches).expect("Matches are always serializable");
+  Ok(Response::builder(200).body(body).build())
+}
+
+/// `/debug/:agency/matches`: how every arrival in the latest poll was matched
+pub async fn matches_route(req: Request<State>) -> tide::Result {
+  Ok(matches_response(&req).unwrap_or_else(Response::from))
+}
+
+#[derive(Serialize)]
+struct Crosswalk<'a> {
+  updated_at: DateTime<Utc>,
+  #[serde(flatten)]
+  report: &'a CrosswalkReport,
+}
+
+fn crosswalk_response(req: &Request<State>) -> Result<Response, ApiError> {
+  let agency = agency(req)?;
+  let snapshot = snapshot(req.state(), agency)?;
+  let crosswalk = Crosswalk {
+    updated_at: snapshot.updated_at.into(),
+    report: &snapshot.crosswalk,
+  };
+  let body = Body::from_json(&crosswalk).expect("Crosswalk reports are always serializable");
+  Ok(Response::builder(200).body(body).build())
+}
+
+/// `/debug/:agency/crosswalk`: how TransLoc routes and stops were joined to
+/// the static GTFS in the latest poll, and which couldn't be
+pub async fn crosswalk_route(req: Request<State>) -> tide::Result {
+  Ok(crosswalk_response(&req).unwrap_or_else(Response::from))
+}
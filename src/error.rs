@@ -0,0 +1,67 @@
+use crate::poller::Failure;
+use serde::Serialize;
+use tide::{Body, Response, StatusCode};
+
+/// Everything a feed route can fail with, rendered as a JSON body so
+/// consumers can tell bad input from upstream trouble.
+#[derive(Debug)]
+pub enum ApiError {
+  BadRequest(String),
+  UnknownAgency(String),
+  /// The agency's first poll hasn't finished yet
+  NotReady(String),
+  /// The agency has never been fetched successfully, and this is why
+  Upstream(Failure),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+  status: u16,
+  error: &'static str,
+  message: &'a str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  source: Option<&'a str>,
+}
+
+impl ApiError {
+  pub fn status(&self) -> StatusCode {
+    match self {
+      Self::BadRequest(_) => StatusCode::BadRequest,
+      Self::UnknownAgency(_) => StatusCode::NotFound,
+      Self::NotReady(_) => StatusCode::ServiceUnavailable,
+      Self::Upstream(failure) => failure.status,
+    }
+  }
+
+  fn kind(&self) -> &'static str {
+    match self {
+      Self::BadRequest(_) => "bad_request",
+      Self::UnknownAgency(_) => "unknown_agency",
+      Self::NotReady(_) => "not_ready",
+      Self::Upstream(_) => "upstream",
+    }
+  }
+}
+
+impl From<ApiError> for Response {
+  fn from(err: ApiError) -> Response {
+    let (message, source) = match &err {
+      ApiError::BadRequest(message)
+      | ApiError::UnknownAgency(message)
+      | ApiError::NotReady(message) => (message.as_str(), None),
+      ApiError::Upstream(failure) => (failure.message.as_str(), Some(failure.source.as_str())),
+    };
+    let body = ErrorBody {
+      status: err.status().into(),
+      error: err.kind(),
+      message,
+      source,
+    };
+    let mut response = Response::new(err.status());
+    match Body::from_json(&body) {
+      Ok(body) => response.set_body(body),
+      Err(json_err) => response.set_body(format!("{message} ({json_err})")),
+    }
+    response
+  }
+}
@@ -0,0 +1,703 @@
+use crate::crosswalk::normalize_name;
+use crate::protobuf_route::{GenFeedError, HTTP};
+use chrono::{Datelike, NaiveDate, Weekday};
+use chrono_tz::Tz;
+use http_cache_reqwest::{CACacheManager, Cache, CacheMode, HttpCache};
+use lazy_static::lazy_static;
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use serde::de;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::Metadata;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+#[derive(Debug, Deserialize)]
+pub struct CSVAgency {
+  pub agency_timezone: Tz,
+}
+
+/// Columns the spec lets feeds leave out (or leave blank) are `Option`s, or
+/// default when there's nothing sensible to tell apart from a blank.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct CSVRoute {
+  pub route_id: u64,
+  /// A route needs a short name, a long name or both
+  #[serde(default)]
+  pub route_short_name: String,
+  #[serde(default)]
+  pub route_long_name: String,
+  pub route_desc: Option<String>,
+  pub route_url: Option<String>,
+  pub route_color: Option<String>,
+  pub route_text_color: Option<String>,
+  pub route_type: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StopTime {
+  pub trip_id: u64,
+  #[serde(deserialize_with = "day_time_deserializer")]
+  pub arrival_time: (String, u64),
+  #[allow(dead_code)]
+  #[serde(deserialize_with = "day_time_deserializer")]
+  pub departure_time: (String, u64),
+  pub stop_id: u64,
+  pub stop_sequence: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct CSVTrip {
+  pub trip_id: u64,
+  pub route_id: u64,
+  pub service_id: u64,
+  #[serde(default)]
+  pub trip_headsign: String,
+  pub trip_short_name: Option<String>,
+  pub direction_id: Option<u64>,
+  pub shape_id: Option<String>,
+  pub wheelchair_accessible: Option<u64>,
+  pub bikes_allowed: Option<u64>,
+  pub block_id: Option<String>,
+  pub block_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CSVFrequency {
+  pub trip_id: u64,
+  #[serde(deserialize_with = "day_time_deserializer")]
+  pub start_time: (String, u64),
+  #[serde(deserialize_with = "day_time_deserializer")]
+  pub end_time: (String, u64),
+  pub headway_secs: u64,
+  #[serde(default, deserialize_with = "blank_as_default")]
+  pub exact_times: u8,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Clone)]
+pub struct CSVStop {
+  pub stop_id: u64,
+  #[serde(default)]
+  pub stop_code: String,
+  #[serde(default)]
+  pub stop_name: String,
+  pub stop_desc: Option<String>,
+  pub stop_lat: f64,
+  pub stop_lon: f64,
+  pub stop_url: Option<String>,
+  /// 0 (a stop or platform) when left out or blank
+  #[serde(default, deserialize_with = "blank_as_default")]
+  pub location_type: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CSVCalendar {
+  pub service_id: u64,
+  pub monday: u8,
+  pub tuesday: u8,
+  pub wednesday: u8,
+  pub thursday: u8,
+  pub friday: u8,
+  pub saturday: u8,
+  pub sunday: u8,
+  #[serde(deserialize_with = "date_deserializer")]
+  pub start_date: NaiveDate,
+  #[serde(deserialize_with = "date_deserializer")]
+  pub end_date: NaiveDate,
+}
+
+impl CSVCalendar {
+  fn runs_on(&self, weekday: Weekday) -> bool {
+    let flag = match weekday {
+      Weekday::Mon => self.monday,
+      Weekday::Tue => self.tuesday,
+      Weekday::Wed => self.wednesday,
+      Weekday::Thu => self.thursday,
+      Weekday::Fri => self.friday,
+      Weekday::Sat => self.saturday,
+      Weekday::Sun => self.sunday,
+    };
+    flag == 1
+  }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CSVCalendarDate {
+  pub service_id: u64,
+  #[serde(deserialize_with = "date_deserializer")]
+  pub date: NaiveDate,
+  /// 1 = service added, 2 = service removed
+  pub exception_type: u8,
+}
+
+fn date_deserializer<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
+where
+  D: de::Deserializer<'de>,
+{
+  let date: String = Deserialize::deserialize(deserializer)?;
+  NaiveDate::parse_from_str(&date, "%Y%m%d")
+    .map_err(|err| de::Error::custom(format!("Failed to deserialize date: {} {}", date, err)))
+}
+
+/// An optional column's value, with a blank one treated like a missing column
+fn blank_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+  D: de::Deserializer<'de>,
+  T: Deserialize<'de> + Default,
+{
+  let value: Option<T> = Deserialize::deserialize(deserializer)?;
+  Ok(value.unwrap_or_default())
+}
+
+/// Parses a GTFS `HH:MM:SS` time into seconds since the start of the service day.
+pub fn parse_day_time(time: &str) -> Option<u64> {
+  let mut parts = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+  let hour = parts.next()??;
+  let minute = parts.next()??;
+  let second = parts.next()??;
+  Some(hour * 3600 + minute * 60 + second)
+}
+
+fn day_time_deserializer<'de, D>(deserializer: D) -> Result<(String, u64), D::Error>
+where
+  D: de::Deserializer<'de>,
+{
+  let time: String = Deserialize::deserialize(deserializer)?;
+  match parse_day_time(&time) {
+    Some(value) => Ok((time, value)),
+    None => Err(de::Error::custom(format!(
+      "Failed to deserialize day_time: {time}"
+    ))),
+  }
+}
+
+pub fn day_time_serializer(total_seconds: u64) -> String {
+  let seconds = total_seconds % 60;
+  let total_minutes = total_seconds / 60;
+  let minutes = total_minutes % 60;
+  let hours = total_minutes / 60;
+  format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+}
+
+fn read_csv<T: DeserializeOwned>(
+  zip: &mut ZipArchive<Cursor<Vec<u8>>>,
+  path: &str,
+) -> Result<Vec<T>, GenFeedError> {
+  let file = zip
+    .by_name(path)
+    .map_err(|err| GenFeedError::Zip(err, path.to_owned()))?;
+  let mut reader = csv::Reader::from_reader(file);
+  let mut rows = vec![];
+  let mut skipped = 0;
+  for row in reader.deserialize() {
+    match row {
+      Ok(row) => rows.push(row),
+      Err(err) => {
+        // One bad row shouldn't cost us the whole feed, but a feed where
+        // every row is bad should be obvious
+        if skipped == 0 {
+          log::warn!("Skipping rows of {path} that don't parse, starting with: {err}");
+        } else {
+          log::debug!("Skipping row of {path}: {err}");
+        }
+        skipped += 1;
+      }
+    }
+  }
+  if skipped > 0 {
+    log::warn!(
+      "Skipped {skipped} of {} rows in {path}",
+      rows.len() + skipped
+    );
+  }
+  Ok(rows)
+}
+
+/// Like [`read_csv`], but for files the spec lets feeds leave out.
+fn read_optional_csv<T: DeserializeOwned>(
+  zip: &mut ZipArchive<Cursor<Vec<u8>>>,
+  path: &str,
+) -> Result<Vec<T>, GenFeedError> {
+  match read_csv(zip, path) {
+    Err(GenFeedError::Zip(ZipError::FileNotFound, _)) => Ok(vec![]),
+    result => result,
+  }
+}
+
+/// The static half of the schedule. It only changes when the agency publishes
+/// a new GTFS zip, so it's parsed and indexed once and shared between polls.
+pub struct Gtfs {
+  pub routes: HashMap<u64, CSVRoute>,
+  pub trips: HashMap<u64, CSVTrip>,
+  pub stops: HashMap<u64, CSVStop>,
+  /// A trip can run at different headways through the day, one row each
+  pub frequencies: HashMap<u64, Vec<CSVFrequency>>,
+  /// trip_id -> stop_times, ordered by stop_sequence
+  trip_stop_times: HashMap<u64, Vec<StopTime>>,
+  /// (trip_id, stop_id) -> indices into `trip_stop_times`. A stop can appear
+  /// more than once in a trip that loops.
+  trip_stop_index: HashMap<(u64, u64), Vec<usize>>,
+  /// (route_id, stop_id) -> visits by trips without frequencies, ordered by
+  /// arrival
+  route_stop_visits: HashMap<(u64, u64), Vec<StopVisit>>,
+  /// route_id -> trip_ids that run on frequencies.txt, in trips.txt order
+  route_frequency_trips: HashMap<u64, Vec<u64>>,
+  /// (route_id, [`headsign_key`]) -> the direction_ids trips with that
+  /// headsign run in
+  route_headsign_directions: HashMap<(u64, String), HashSet<u64>>,
+  /// agency_timezone from agency.txt, which every agency in a feed shares
+  pub timezone: Option<Tz>,
+  /// service_id -> calendar.txt row
+  calendar: HashMap<u64, CSVCalendar>,
+  /// (service_id, date) -> calendar_dates.txt exception_type
+  calendar_dates: HashMap<(u64, NaiveDate), u8>,
+  /// Trimmed route_short_name -> route_ids, for the crosswalk
+  route_short_names: HashMap<String, Vec<u64>>,
+  /// [`normalize_name`]d route_long_name -> route_ids
+  route_long_names: HashMap<String, Vec<u64>>,
+  /// Trimmed stop_code -> stop_ids
+  stop_codes: HashMap<String, Vec<u64>>,
+  /// [`normalize_name`]d stop_name -> stop_ids
+  stop_names: HashMap<String, Vec<u64>>,
+}
+
+/// A trip's stop_time at a stop, for looking up what arrives there when
+struct StopVisit {
+  /// Seconds since the start of the service day
+  arrival: u64,
+  trip_id: u64,
+  /// Index into the trip's `trip_stop_times`
+  index: usize,
+}
+
+/// Groups ids by a key, leaving out empty keys. Ids are sorted.
+fn index_by<'a, T: 'a>(
+  items: impl IntoIterator<Item = &'a T>,
+  key: impl Fn(&T) -> String,
+  id: impl Fn(&T) -> u64,
+) -> HashMap<String, Vec<u64>> {
+  let mut index: HashMap<String, Vec<u64>> = HashMap::new();
+  for item in items {
+    let key = key(item);
+    if !key.is_empty() {
+      index.entry(key).or_default().push(id(item));
+    }
+  }
+  for ids in index.values_mut() {
+    ids.sort_unstable();
+  }
+  index
+}
+
+impl Gtfs {
+  pub fn from_zip(bytes: Vec<u8>) -> Result<Gtfs, GenFeedError> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))
+      .map_err(|err| GenFeedError::Zip(err, "GTFS zip".to_owned()))?;
+
+    let agencies: Vec<CSVAgency> = read_csv(&mut zip, "agency.txt")?;
+    let timezone = agencies.first().map(|agency| agency.agency_timezone);
+    let routes: Vec<CSVRoute> = read_csv(&mut zip, "routes.txt")?;
+    let stop_times: Vec<StopTime> = read_csv(&mut zip, "stop_times.txt")?;
+    let trips: Vec<CSVTrip> = read_csv(&mut zip, "trips.txt")?;
+    let routes = HashMap::from_iter(routes.into_iter().map(|route| (route.route_id, route)));
+    let stops: Vec<CSVStop> = read_csv(&mut zip, "stops.txt")?;
+    let stops = HashMap::from_iter(stops.into_iter().map(|stop| (stop.stop_id, stop)));
+    let frequencies: Vec<CSVFrequency> = read_optional_csv(&mut zip, "frequencies.txt")?;
+    let mut trip_frequencies: HashMap<u64, Vec<CSVFrequency>> = HashMap::new();
+    for frequency in frequencies {
+      trip_frequencies
+        .entry(frequency.trip_id)
+        .or_default()
+        .push(frequency);
+    }
+
+    let mut route_frequency_trips: HashMap<u64, Vec<u64>> = HashMap::new();
+    for trip in &trips {
+      if trip_frequencies.contains_key(&trip.trip_id) {
+        route_frequency_trips
+          .entry(trip.route_id)
+          .or_default()
+          .push(trip.trip_id);
+      }
+    }
+    let mut route_headsign_directions: HashMap<(u64, String), HashSet<u64>> = HashMap::new();
+    for trip in &trips {
+      if let Some(direction_id) = trip.direction_id {
+        route_headsign_directions
+          .entry((trip.route_id, headsign_key(&trip.trip_headsign)))
+          .or_default()
+          .insert(direction_id);
+      }
+    }
+    let trips = HashMap::from_iter(trips.into_iter().map(|trip| (trip.trip_id, trip)));
+
+    let mut trip_stop_times: HashMap<u64, Vec<StopTime>> = HashMap::new();
+    for stop_time in stop_times {
+      trip_stop_times
+        .entry(stop_time.trip_id)
+        .or_default()
+        .push(stop_time);
+    }
+    let mut trip_stop_index: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    let mut route_stop_visits: HashMap<(u64, u64), Vec<StopVisit>> = HashMap::new();
+    for (trip_id, stop_times) in trip_stop_times.iter_mut() {
+      stop_times.sort_by_key(|stop_time| stop_time.stop_sequence);
+      // A frequency-based trip's times are only a template for its runs
+      let route_id = trips
+        .get(trip_id)
+        .filter(|_| !trip_frequencies.contains_key(trip_id))
+        .map(|trip| trip.route_id);
+      for (index, stop_time) in stop_times.iter().enumerate() {
+        trip_stop_index
+          .entry((*trip_id, stop_time.stop_id))
+          .or_default()
+          .push(index);
+        if let Some(route_id) = route_id {
+          route_stop_visits
+            .entry((route_id, stop_time.stop_id))
+            .or_default()
+            .push(StopVisit {
+              arrival: stop_time.arrival_time.1,
+              trip_id: *trip_id,
+              index,
+            });
+        }
+      }
+    }
+    for visits in route_stop_visits.values_mut() {
+      visits.sort_unstable_by_key(|visit| visit.arrival);
+    }
+
+    let calendar: Vec<CSVCalendar> = read_optional_csv(&mut zip, "calendar.txt")?;
+    let calendar = HashMap::from_iter(
+      calendar
+        .into_iter()
+        .map(|calendar| (calendar.service_id, calendar)),
+    );
+    let calendar_dates: Vec<CSVCalendarDate> = read_optional_csv(&mut zip, "calendar_dates.txt")?;
+    let calendar_dates = HashMap::from_iter(calendar_dates.into_iter().map(|exception| {
+      (
+        (exception.service_id, exception.date),
+        exception.exception_type,
+      )
+    }));
+
+    let route_short_names = index_by(
+      routes.values(),
+      |route: &CSVRoute| route.route_short_name.trim().to_owned(),
+      |route| route.route_id,
+    );
+    let route_long_names = index_by(
+      routes.values(),
+      |route: &CSVRoute| normalize_name(&route.route_long_name),
+      |route| route.route_id,
+    );
+    let stop_codes = index_by(
+      stops.values(),
+      |stop: &CSVStop| stop.stop_code.trim().to_owned(),
+      |stop| stop.stop_id,
+    );
+    let stop_names = index_by(
+      stops.values(),
+      |stop: &CSVStop| normalize_name(&stop.stop_name),
+      |stop| stop.stop_id,
+    );
+
+    Ok(Gtfs {
+      routes,
+      trips,
+      stops,
+      frequencies: trip_frequencies,
+      trip_stop_times,
+      trip_stop_index,
+      route_stop_visits,
+      route_frequency_trips,
+      route_headsign_directions,
+      timezone,
+      calendar,
+      calendar_dates,
+      route_short_names,
+      route_long_names,
+      stop_codes,
+      stop_names,
+    })
+  }
+
+  /// The trips on `route_id` that run on frequencies.txt.
+  pub fn frequency_trips_for_route(&self, route_id: u64) -> impl Iterator<Item = &CSVTrip> {
+    self
+      .route_frequency_trips
+      .get(&route_id)
+      .into_iter()
+      .flatten()
+      .filter_map(|trip_id| self.trips.get(trip_id))
+  }
+
+  /// The stop_times where trips on `route_id` without frequencies serve
+  /// `stop_id`, arriving between `from` and `to` seconds into the service
+  /// day (inclusive), earliest first.
+  pub fn stop_times_between(
+    &self,
+    route_id: u64,
+    stop_id: u64,
+    from: u64,
+    to: u64,
+  ) -> impl Iterator<Item = &StopTime> {
+    let visits = self
+      .route_stop_visits
+      .get(&(route_id, stop_id))
+      .map_or(&[][..], |visits| visits.as_slice());
+    let start = visits.partition_point(|visit| visit.arrival < from);
+    visits[start..]
+      .iter()
+      .take_while(move |visit| visit.arrival <= to)
+      .map(|visit| &self.trip_stop_times[&visit.trip_id][visit.index])
+  }
+
+  /// The direction_ids of trips on `route_id` with a headsign of `headsign`,
+  /// ignoring case and surrounding whitespace.
+  pub fn headsign_directions(&self, route_id: u64, headsign: &str) -> Option<&HashSet<u64>> {
+    self
+      .route_headsign_directions
+      .get(&(route_id, headsign_key(headsign)))
+  }
+
+  /// Whether `service_id` runs on the service day `date`, taking
+  /// calendar_dates.txt exceptions (holidays, breaks) into account.
+  pub fn is_service_active(&self, service_id: u64, date: NaiveDate) -> bool {
+    if self.calendar.is_empty() && self.calendar_dates.is_empty() {
+      // No calendar at all, so there's nothing to filter on
+      return true;
+    }
+    match self.calendar_dates.get(&(service_id, date)) {
+      Some(1) => true,
+      Some(2) => false,
+      _ => self.calendar.get(&service_id).map_or(false, |calendar| {
+        calendar.start_date <= date && date <= calendar.end_date && calendar.runs_on(date.weekday())
+      }),
+    }
+  }
+
+  /// The route_ids with a trimmed route_short_name of `short_name`.
+  pub fn routes_by_short_name(&self, short_name: &str) -> &[u64] {
+    lookup(&self.route_short_names, short_name)
+  }
+
+  /// The route_ids whose route_long_name normalizes to `long_name`.
+  pub fn routes_by_long_name(&self, long_name: &str) -> &[u64] {
+    lookup(&self.route_long_names, long_name)
+  }
+
+  /// The stop_ids with a trimmed stop_code of `code`.
+  pub fn stops_by_code(&self, code: &str) -> &[u64] {
+    lookup(&self.stop_codes, code)
+  }
+
+  /// The stop_ids whose stop_name normalizes to `name`.
+  pub fn stops_by_name(&self, name: &str) -> &[u64] {
+    lookup(&self.stop_names, name)
+  }
+
+  /// Every stop_time of a trip, ordered by stop_sequence.
+  pub fn stop_times(&self, trip_id: u64) -> &[StopTime] {
+    self
+      .trip_stop_times
+      .get(&trip_id)
+      .map_or(&[], |stop_times| stop_times.as_slice())
+  }
+
+  /// The stop_times where `trip_id` serves `stop_id`.
+  pub fn stop_times_at(&self, trip_id: u64, stop_id: u64) -> impl Iterator<Item = &StopTime> {
+    let stop_times = self.stop_times(trip_id);
+    self
+      .trip_stop_index
+      .get(&(trip_id, stop_id))
+      .into_iter()
+      .flatten()
+      .map(move |index| &stop_times[*index])
+  }
+}
+
+/// How headsigns are compared: TransLoc and the GTFS don't always agree on
+/// case or padding.
+pub fn headsign_key(headsign: &str) -> String {
+  headsign.trim().to_ascii_lowercase()
+}
+
+fn lookup<'a>(index: &'a HashMap<String, Vec<u64>>, key: &str) -> &'a [u64] {
+  index.get(key).map_or(&[], |ids| ids.as_slice())
+}
+
+/// Where an agency's static GTFS zip comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GtfsSource {
+  Url(String),
+  Path(PathBuf),
+}
+
+impl fmt::Display for GtfsSource {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Url(url) => write!(f, "{url}"),
+      Self::Path(path) => write!(f, "{}", path.display()),
+    }
+  }
+}
+
+/// What we know about the zip a [`Gtfs`] was parsed from, used to tell
+/// whether a fresh download actually changed anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Validators {
+  etag: Option<String>,
+  last_modified: Option<String>,
+}
+
+impl Validators {
+  fn from_headers(headers: &HeaderMap) -> Validators {
+    let header = |name| {
+      headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+    };
+    Validators {
+      etag: header(ETAG),
+      last_modified: header(LAST_MODIFIED),
+    }
+  }
+
+  fn from_metadata(metadata: &Metadata) -> Validators {
+    Validators {
+      etag: None,
+      last_modified: metadata
+        .modified()
+        .ok()
+        .map(|modified| format!("{modified:?}")),
+    }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.etag.is_none() && self.last_modified.is_none()
+  }
+}
+
+pub struct LoadedGtfs {
+  validators: Validators,
+  hash: u64,
+  pub gtfs: Arc<Gtfs>,
+}
+
+impl LoadedGtfs {
+  fn unchanged(&self, validators: &Validators) -> bool {
+    !validators.is_empty() && *validators == self.validators
+  }
+}
+
+lazy_static! {
+  static ref CACHING_HTTP: ClientWithMiddleware = ClientBuilder::new(HTTP.clone())
+    .with(Cache(HttpCache {
+      mode: CacheMode::Default,
+      manager: CACacheManager::default(),
+      options: None,
+    }))
+    .build();
+}
+
+/// Downloads the zip unless its validators match `previous`, in which case
+/// only the validators are returned.
+async fn fetch_url(
+  url: &str,
+  previous: Option<&LoadedGtfs>,
+) -> Result<(Validators, Option<Vec<u8>>), GenFeedError> {
+  let response = CACHING_HTTP
+    .get(url)
+    .send()
+    .await
+    .map_err(|err| GenFeedError::ZipHttp(err, url.to_owned()))?
+    .error_for_status()
+    .map_err(|err| GenFeedError::Http(err, url.to_owned()))?;
+  let validators = Validators::from_headers(response.headers());
+  if previous.map_or(false, |previous| previous.unchanged(&validators)) {
+    return Ok((validators, None));
+  }
+  let bytes = response
+    .bytes()
+    .await
+    .map_err(|err| GenFeedError::Http(err, url.to_owned()))?;
+  Ok((validators, Some(Vec::from(bytes))))
+}
+
+/// Like [`fetch_url`], using the file's modification time as its validator.
+async fn read_path(
+  path: &Path,
+  previous: Option<&LoadedGtfs>,
+) -> Result<(Validators, Option<Vec<u8>>), GenFeedError> {
+  let io_error = |err| GenFeedError::Io(err, path.display().to_string());
+  let metadata = async_std::fs::metadata(path).await.map_err(io_error)?;
+  let validators = Validators::from_metadata(&metadata);
+  if previous.map_or(false, |previous| previous.unchanged(&validators)) {
+    return Ok((validators, None));
+  }
+  let bytes = async_std::fs::read(path).await.map_err(io_error)?;
+  Ok((validators, Some(bytes)))
+}
+
+/// Fetches the static GTFS zip, only re-parsing it when its cache validators
+/// or its contents differ from `previous`.
+pub async fn load_gtfs(
+  source: &GtfsSource,
+  previous: Option<&LoadedGtfs>,
+) -> Result<LoadedGtfs, GenFeedError> {
+  let (validators, bytes) = match source {
+    GtfsSource::Url(url) => fetch_url(url, previous).await?,
+    GtfsSource::Path(path) => read_path(path, previous).await?,
+  };
+  let (previous, bytes) = match (previous, bytes) {
+    (Some(previous), None) => {
+      log::debug!("GTFS at {source} is unchanged ({validators:?})");
+      return Ok(LoadedGtfs {
+        validators,
+        hash: previous.hash,
+        gtfs: previous.gtfs.clone(),
+      });
+    }
+    (previous, Some(bytes)) => (previous, bytes),
+    (None, None) => unreachable!("Nothing to compare validators against"),
+  };
+
+  let mut hasher = DefaultHasher::new();
+  bytes.hash(&mut hasher);
+  let hash = hasher.finish();
+  if let Some(previous) = previous {
+    if previous.hash == hash {
+      log::debug!("GTFS at {source} has new validators but the same contents");
+      return Ok(LoadedGtfs {
+        validators,
+        hash,
+        gtfs: previous.gtfs.clone(),
+      });
+    }
+  }
+
+  log::info!("Parsing GTFS from {source}");
+  let gtfs = Gtfs::from_zip(bytes).map_err(|err| match err {
+    GenFeedError::Zip(err, entry) => GenFeedError::Zip(err, format!("{source} ({entry})")),
+    err => err,
+  })?;
+  Ok(LoadedGtfs {
+    validators,
+    hash,
+    gtfs: Arc::new(gtfs),
+  })
+}
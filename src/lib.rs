@@ -0,0 +1,27 @@
+pub mod alerts;
+pub mod arrivals;
+pub mod config;
+pub mod crosswalk;
+pub mod crosswalk_cli;
+pub mod debug;
+pub mod error;
+pub mod gtfs;
+pub mod poller;
+pub mod protobuf_route;
+pub mod registry;
+pub mod schedule;
+pub mod tracker;
+pub mod traits;
+pub mod uncertainty;
+
+use crate::config::Config;
+use crate::poller::Snapshots;
+use crate::registry::Registry;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct State {
+  pub config: Arc<Config>,
+  pub registry: Arc<Registry>,
+  pub snapshots: Snapshots,
+}
@@ -1,19 +1,47 @@
 // #![feature(future_join)]
 
-mod alerts;
-mod arrivals;
-mod protobuf_route;
-mod schedule;
-mod traits;
-use crate::protobuf_route::protobuf_route;
+use clap::Parser;
+use rit_gtfsrt::config::{Args, Command, Config};
+use rit_gtfsrt::crosswalk_cli;
+use rit_gtfsrt::debug::{crosswalk_route, matches_route};
+use rit_gtfsrt::poller::{spawn_pollers, Snapshots};
+use rit_gtfsrt::protobuf_route::{legacy_protobuf_route, protobuf_route, FeedKind};
+use rit_gtfsrt::registry::{index_route, Registry};
+use rit_gtfsrt::State;
+use std::sync::Arc;
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
   env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
-  let mut app = tide::new();
+  let mut args = Args::parse();
+  let command = args.command.take();
+  let config = Config::load(args)?;
+  let addr = config.listen.clone();
+  let registry = Arc::new(Registry::load(&config).await?);
+  if let Some(Command::Crosswalk(args)) = command {
+    crosswalk_cli::run(&config, &registry, args).await?;
+    return Ok(());
+  }
+  let config = Arc::new(config);
+  let snapshots = Snapshots::default();
+  spawn_pollers(config.clone(), registry.clone(), snapshots.clone());
+  let mut app = tide::with_state(State {
+    config,
+    registry,
+    snapshots,
+  });
   app.with(tide::log::LogMiddleware::new());
-  app.at("/rt/:agency_id/:agency_code").get(protobuf_route);
-  let addr = "0.0.0.0:6969";
+  app.at("/rt").get(index_route);
+  for kind in FeedKind::ALL {
+    app
+      .at(&format!("/rt/:agency{}", kind.path()))
+      .get(move |req| protobuf_route(req, kind));
+  }
+  app
+    .at("/rt/:agency_id/:agency_code")
+    .get(legacy_protobuf_route);
+  app.at("/debug/:agency/matches").get(matches_route);
+  app.at("/debug/:agency/crosswalk").get(crosswalk_route);
   println!("Ready to go at: http://{}", addr);
   app.listen(addr).await?;
   Ok(())
@@ -0,0 +1,164 @@
+use crate::config::Config;
+use crate::crosswalk::{CrosswalkReport, StopRule};
+use crate::debug::ArrivalMatch;
+use crate::gtfs::{load_gtfs, LoadedGtfs};
+use crate::protobuf_route::{build_snapshot, GenFeedError};
+use crate::registry::{Agency, Registry};
+use crate::schedule::ScheduleCache;
+use crate::tracker::VehicleTracker;
+use crate::uncertainty::PredictionHistory;
+use async_std::task;
+use gtfs_rt::FeedEntity;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tide::StatusCode;
+
+/// One kind of entity, and when we fetched it.
+#[derive(Clone)]
+pub struct FeedPart {
+  pub entity: Vec<FeedEntity>,
+  pub timestamp: u64,
+}
+
+pub struct Snapshot {
+  /// The latest announcements we managed to fetch, or why we've never
+  /// managed to
+  pub alerts: Result<FeedPart, Failure>,
+  pub trip_updates: FeedPart,
+  pub vehicle_positions: FeedPart,
+  /// How TransLoc entities were joined to the static GTFS, for
+  /// `/debug/:agency/crosswalk`
+  pub crosswalk: CrosswalkReport,
+  /// How each TransLoc arrival was matched, for `/debug/:agency/matches`
+  pub matches: Vec<ArrivalMatch>,
+  pub updated_at: SystemTime,
+}
+
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Can't get time")
+    .as_secs()
+}
+
+/// Why the last poll of an agency failed.
+#[derive(Debug, Clone)]
+pub struct Failure {
+  pub status: StatusCode,
+  pub source: String,
+  pub message: String,
+  pub failed_at: SystemTime,
+}
+
+impl From<&GenFeedError> for Failure {
+  fn from(err: &GenFeedError) -> Failure {
+    Failure {
+      status: err.status(),
+      source: err.source_name().to_owned(),
+      message: err.to_string(),
+      failed_at: SystemTime::now(),
+    }
+  }
+}
+
+#[derive(Default, Clone)]
+pub struct FeedStatus {
+  /// The latest feed we managed to build
+  pub snapshot: Option<Arc<Snapshot>>,
+  /// Set when the most recent poll failed
+  pub failure: Option<Failure>,
+}
+
+/// Feed status for every polled agency, keyed by agency code.
+pub type Snapshots = Arc<RwLock<HashMap<String, FeedStatus>>>;
+
+fn record(snapshots: &Snapshots, agency: &Agency, update: impl FnOnce(&mut FeedStatus)) {
+  let mut snapshots = snapshots.write().expect("Snapshots lock poisoned");
+  update(snapshots.entry(agency.code().to_owned()).or_default());
+}
+
+pub fn spawn_pollers(config: Arc<Config>, registry: Arc<Registry>, snapshots: Snapshots) {
+  for agency in registry.iter() {
+    task::spawn(poll(config.clone(), agency.clone(), snapshots.clone()));
+  }
+}
+
+async fn poll(config: Arc<Config>, agency: Arc<Agency>, snapshots: Snapshots) {
+  let interval = config.poll_interval(&agency.settings);
+  let gtfs_source = config.gtfs_source(&agency.settings);
+  let mut gtfs: Option<LoadedGtfs> = None;
+  let mut tracker = VehicleTracker::default();
+  let mut history = PredictionHistory::default();
+  let mut cache = ScheduleCache::default();
+  loop {
+    match load_gtfs(&gtfs_source, gtfs.as_ref()).await {
+      Ok(loaded) => gtfs = Some(loaded),
+      // Keep serving the schedule we already have, if any
+      Err(err) => {
+        log::error!("Couldn't refresh GTFS for {}: {err}", agency.code());
+        record(&snapshots, &agency, |status| {
+          status.failure = Some((&err).into())
+        });
+      }
+    }
+    let previous = snapshots
+      .read()
+      .expect("Snapshots lock poisoned")
+      .get(agency.code())
+      .and_then(|status| status.snapshot.clone());
+    let snapshot = match &gtfs {
+      Some(loaded) => {
+        build_snapshot(
+          &config,
+          &agency,
+          loaded.gtfs.clone(),
+          previous.as_deref(),
+          &mut tracker,
+          &mut history,
+          &mut cache,
+        )
+        .await
+      }
+      None => {
+        task::sleep(interval).await;
+        continue;
+      }
+    };
+    match snapshot {
+      Ok(snapshot) => {
+        let report = &snapshot.crosswalk;
+        let reported = previous.as_ref().map(|previous| &previous.crosswalk);
+        if report.has_problems()
+          && !reported.map_or(false, |reported| reported.same_problems(report))
+        {
+          log::warn!("Crosswalk for {} is incomplete: {report}", agency.code());
+        }
+        if reported.map_or(true, |reported| reported.stops != report.stops) {
+          for (transloc_id, stop_match) in &report.stops {
+            if stop_match.rule != StopRule::Code {
+              log::info!(
+                "{}: TransLoc stop {transloc_id} is GTFS stop {} by {:?} ({:.0}m apart)",
+                agency.code(),
+                stop_match.stop_id,
+                stop_match.rule,
+                stop_match.distance
+              );
+            }
+          }
+        }
+        record(&snapshots, &agency, |status| {
+          status.snapshot = Some(Arc::new(snapshot));
+          status.failure = None;
+        });
+      }
+      Err(err) => {
+        log::error!("Couldn't refresh feed for {}: {err}", agency.code());
+        record(&snapshots, &agency, |status| {
+          status.failure = Some((&err).into())
+        });
+      }
+    }
+    task::sleep(interval).await;
+  }
+}
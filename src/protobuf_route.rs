@@ -1,56 +1,286 @@
 use crate::alerts::get_alerts;
 use crate::arrivals::get_trip_arrivals;
-use crate::schedule::get_schedule;
+use crate::config::Config;
+use crate::crosswalk::CrosswalkReport;
+use crate::debug::ArrivalMatch;
+use crate::error::ApiError;
+use crate::gtfs::{parse_day_time, Gtfs};
+use crate::poller::{now, Failure, FeedPart, FeedStatus, Snapshot};
+use crate::registry::Agency;
+use crate::schedule::{get_schedule, MatchTrace, ScheduleCache};
+use crate::tracker::VehicleTracker;
+use crate::uncertainty::PredictionHistory;
+use crate::State;
+use chrono::{DateTime, Utc};
 use gtfs_rt::{feed_header::Incrementality, FeedEntity, FeedHeader, FeedMessage};
+use lazy_static::lazy_static;
 use prost::Message;
+use reqwest::Client;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::error::Error;
 use std::fmt;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tide::{Request, Response};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tide::{Request, Response, StatusCode};
 use zip::result::ZipError;
 
+/// Which of `time` and `delay` a consumer wants in each StopTimeEvent
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeProfile {
+  Delay,
+  Time,
+  #[default]
+  Both,
+}
+
 #[derive(Deserialize, Default)]
 #[serde(default)]
 struct QueryParams {
   transit_workaround: bool,
+  /// Leave trip updates out, for consumers that only want vehicle positions
+  positions_only: bool,
+  times: TimeProfile,
+}
+
+fn param<'a>(req: &'a Request<State>, name: &str) -> Result<&'a str, ApiError> {
+  req
+    .param(name)
+    .map_err(|_| ApiError::BadRequest(format!("Missing {name} url param")))
+}
+
+fn query(req: &Request<State>) -> Result<QueryParams, ApiError> {
+  req
+    .query()
+    .map_err(|err| ApiError::BadRequest(format!("Invalid query parameters: {err}")))
+}
+
+/// The agency named by `/rt/:agency`
+pub(crate) fn agency(req: &Request<State>) -> Result<&Arc<Agency>, ApiError> {
+  let agency_code = param(req, "agency")?;
+  req
+    .state()
+    .registry
+    .get(agency_code)
+    .ok_or_else(|| ApiError::UnknownAgency(format!("Agency {agency_code} isn't configured")))
 }
 
-pub async fn protobuf_route(req: Request<()>) -> tide::Result {
-  let agency_id: u64 = req
-    .param("agency_id")
-    .expect("missing agency_id url param")
+/// The agency named by `/rt/:agency_id/:agency_code`
+fn legacy_agency(req: &Request<State>) -> Result<&Arc<Agency>, ApiError> {
+  let agency_id = param(req, "agency_id")?;
+  let agency_id: u64 = agency_id
     .parse()
-    .expect("agency_id url param should be a u64");
-  let agency_code = req
-    .param("agency_code")
-    .expect("missing agency_code url param");
-  let query: QueryParams = req.query().expect("Invalid query parameters");
-
-  let feed = get_feed(agency_id, agency_code, query.transit_workaround).await;
-  if let Err(msg) = &feed {
-    eprintln!("Error: {:?}", msg);
-    eprintln!("Error: {}", msg);
-    if let Err(msg) = feed {
-      return Err(msg.into());
+    .map_err(|_| ApiError::BadRequest(format!("agency_id should be a number, not {agency_id}")))?;
+  let agency_code = param(req, "agency_code")?;
+  req
+    .state()
+    .registry
+    .get(agency_code)
+    .filter(|agency| agency.id == agency_id)
+    .ok_or_else(|| {
+      ApiError::UnknownAgency(format!("Agency {agency_id}/{agency_code} isn't configured"))
+    })
+}
+
+/// Which entities a feed URL serves. Some consumers (Google's ingestion among
+/// them) want a separate feed per entity type.
+#[derive(Debug, Clone, Copy)]
+pub enum FeedKind {
+  Combined,
+  TripUpdates,
+  VehiclePositions,
+  Alerts,
+}
+
+impl FeedKind {
+  pub const ALL: [FeedKind; 4] = [
+    FeedKind::Combined,
+    FeedKind::TripUpdates,
+    FeedKind::VehiclePositions,
+    FeedKind::Alerts,
+  ];
+
+  /// Path under `/rt/:agency`
+  pub fn path(self) -> &'static str {
+    match self {
+      Self::Combined => "",
+      Self::TripUpdates => "/trip-updates",
+      Self::VehiclePositions => "/vehicle-positions",
+      Self::Alerts => "/alerts",
     }
   }
-  // if let Err(GenFeedError::Http(err, url)) = &feed {
-  //   println!("Errenous url: {:?}", err.url());
-  // }
-  // println!("Feed is: {:?}", feed);
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Combined => "combined",
+      Self::TripUpdates => "trip_updates",
+      Self::VehiclePositions => "vehicle_positions",
+      Self::Alerts => "alerts",
+    }
+  }
+
+  /// The parts this feed is made of. Alerts that have never been fetched are
+  /// an error for the alerts feed, but trip updates and positions don't wait
+  /// on them.
+  fn parts(self, snapshot: &Snapshot) -> Result<Vec<&FeedPart>, ApiError> {
+    let alerts = snapshot
+      .alerts
+      .as_ref()
+      .map_err(|failure| ApiError::Upstream(failure.clone()));
+    Ok(match self {
+      Self::Combined => alerts
+        .ok()
+        .into_iter()
+        .chain([&snapshot.trip_updates, &snapshot.vehicle_positions])
+        .collect(),
+      Self::TripUpdates => vec![&snapshot.trip_updates],
+      Self::VehiclePositions => vec![&snapshot.vehicle_positions],
+      Self::Alerts => vec![alerts?],
+    })
+  }
+
+  /// The feed, stamped with when its oldest part was fetched.
+  pub fn message(self, snapshot: &Snapshot) -> Result<FeedMessage, ApiError> {
+    let parts = self.parts(snapshot)?;
+    Ok(FeedMessage {
+      header: FeedHeader {
+        gtfs_realtime_version: "2.0".to_owned(),
+        incrementality: Some(Incrementality::FullDataset.into()),
+        timestamp: parts.iter().map(|part| part.timestamp).min(),
+      },
+      entity: parts
+        .into_iter()
+        .flat_map(|part| part.entity.iter().cloned())
+        .collect(),
+    })
+  }
+}
+
+pub async fn protobuf_route(req: Request<State>, kind: FeedKind) -> tide::Result {
+  let response = agency(&req).and_then(|agency| feed_response(&req, agency, kind));
+  Ok(response.unwrap_or_else(Response::from))
+}
+
+/// `/rt/:agency_id/:agency_code`, from before agencies could be looked up by
+/// code alone.
+pub async fn legacy_protobuf_route(req: Request<State>) -> tide::Result {
+  let response =
+    legacy_agency(&req).and_then(|agency| feed_response(&req, agency, FeedKind::Combined));
+  Ok(response.unwrap_or_else(Response::from))
+}
+
+/// The agency's latest snapshot, or why there isn't one.
+pub(crate) fn snapshot(state: &State, agency: &Agency) -> Result<Arc<Snapshot>, ApiError> {
+  let snapshots = state.snapshots.read().expect("Snapshots lock poisoned");
+  let status = snapshots.get(agency.code()).cloned().unwrap_or_default();
+  match status {
+    FeedStatus {
+      snapshot: Some(snapshot),
+      ..
+    } => Ok(snapshot),
+    FeedStatus {
+      failure: Some(failure),
+      ..
+    } => Err(ApiError::Upstream(failure)),
+    _ => Err(ApiError::NotReady(format!(
+      "Feed for {} hasn't been fetched yet",
+      agency.code()
+    ))),
+  }
+}
+
+fn feed_response(
+  req: &Request<State>,
+  agency: &Agency,
+  kind: FeedKind,
+) -> Result<Response, ApiError> {
+  let query = query(req)?;
+  let snapshot = snapshot(req.state(), agency)?;
+
+  let mut feed = kind.message(&snapshot)?;
+  if query.positions_only {
+    feed.entity.retain(|entity| entity.trip_update.is_none());
+  }
+  if query.transit_workaround {
+    apply_transit_workaround(&mut feed);
+  }
+  apply_time_profile(&mut feed, query.times);
+  let updated_at: DateTime<Utc> = snapshot.updated_at.into();
+  let age = SystemTime::now()
+    .duration_since(snapshot.updated_at)
+    .unwrap_or_default();
   Ok(
     Response::builder(200)
-      .body(Message::encode_to_vec(&feed?))
+      .body(Message::encode_to_vec(&feed))
       .content_type("application/vnd.google.protobuf")
+      .header("Age", age.as_secs().to_string())
+      .header(
+        "Last-Modified",
+        updated_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+      )
       .build(),
   )
 }
 
+/// Transit can't handle frequency-based trips, so give every trip instance
+/// its own trip_id and drop the start_time.
+fn apply_transit_workaround(feed: &mut FeedMessage) {
+  let descriptors = feed.entity.iter_mut().flat_map(|entity| {
+    let trip_update = entity.trip_update.as_mut().map(|update| &mut update.trip);
+    let vehicle = entity
+      .vehicle
+      .as_mut()
+      .and_then(|vehicle| vehicle.trip.as_mut());
+    trip_update.into_iter().chain(vehicle)
+  });
+  for descriptor in descriptors {
+    let start_time = match descriptor.start_time.as_deref().and_then(parse_day_time) {
+      Some(start_time) => start_time,
+      None => continue,
+    };
+    if let Some(trip_id) = &descriptor.trip_id {
+      descriptor.trip_id = Some(format!("{trip_id}_{start_time}"));
+    }
+    descriptor.start_time = None;
+  }
+}
+
+/// Drops whichever of `time` and `delay` the consumer didn't ask for. Events
+/// with no delay (frequency-based trips) keep their time either way, since an
+/// event needs one or the other.
+pub fn apply_time_profile(feed: &mut FeedMessage, profile: TimeProfile) {
+  let events = feed
+    .entity
+    .iter_mut()
+    .filter_map(|entity| entity.trip_update.as_mut())
+    .flat_map(|update| update.stop_time_update.iter_mut())
+    .flat_map(|update| update.arrival.iter_mut().chain(update.departure.iter_mut()));
+  for event in events {
+    match profile {
+      TimeProfile::Delay if event.delay.is_some() => event.time = None,
+      TimeProfile::Time if event.time.is_some() => event.delay = None,
+      _ => {}
+    }
+  }
+}
+
+/// How long we wait on TransLoc (or the static GTFS host) before giving up
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+  pub static ref HTTP: Client = Client::builder()
+    .timeout(UPSTREAM_TIMEOUT)
+    .build()
+    .expect("Couldn't build HTTP client");
+}
+
 pub async fn request<T: DeserializeOwned>(url: &str) -> Result<T, GenFeedError> {
-  reqwest::get(url)
+  HTTP
+    .get(url)
+    .send()
     .await
+    .and_then(|response| response.error_for_status())
     .map_err(|err| GenFeedError::Http(err, url.to_string()))?
     .text()
     .await
@@ -58,50 +288,118 @@ pub async fn request<T: DeserializeOwned>(url: &str) -> Result<T, GenFeedError>
     .and_then(|text| {
       let jd = &mut serde_json::Deserializer::from_str(&text);
 
-      serde_path_to_error::deserialize(jd).map_err(GenFeedError::Deserialize)
+      serde_path_to_error::deserialize(jd)
+        .map_err(|err| GenFeedError::Deserialize(err, url.to_string()))
     })
 }
 
 #[derive(Debug)]
 pub enum GenFeedError {
-  Zip(ZipError),
+  Zip(ZipError, String),
   ZipHttp(reqwest_middleware::Error, String),
+  Io(std::io::Error, String),
   Http(reqwest::Error, String),
-  Deserialize(serde_path_to_error::Error<serde_json::Error>),
+  Deserialize(serde_path_to_error::Error<serde_json::Error>, String),
+}
+
+impl GenFeedError {
+  /// The status to answer with when this is why we have no feed: 504 if the
+  /// upstream timed out, 502 if it failed or sent us garbage.
+  pub fn status(&self) -> StatusCode {
+    let timed_out = match self {
+      Self::Http(err, _) => err.is_timeout(),
+      Self::ZipHttp(reqwest_middleware::Error::Reqwest(err), _) => err.is_timeout(),
+      _ => false,
+    };
+    match self {
+      _ if timed_out => StatusCode::GatewayTimeout,
+      Self::Io(..) => StatusCode::InternalServerError,
+      _ => StatusCode::BadGateway,
+    }
+  }
+
+  /// The URL (or file) that failed.
+  pub fn source_name(&self) -> &str {
+    match self {
+      Self::Zip(_, source)
+      | Self::ZipHttp(_, source)
+      | Self::Io(_, source)
+      | Self::Http(_, source)
+      | Self::Deserialize(_, source) => source,
+    }
+  }
 }
 impl Error for GenFeedError {}
 impl fmt::Display for GenFeedError {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
-      Self::Zip(err) => write!(f, "GenFeedError(Zip({err}))"),
+      Self::Zip(err, source) => write!(f, "GenFeedError(Zip({err}, {source}))"),
       Self::ZipHttp(err, url) => write!(f, "GenFeedError(ZipHttp({err}, {url}))"),
+      Self::Io(err, path) => write!(f, "GenFeedError(Io({err}, {path}))"),
       Self::Http(err, url) => write!(f, "GenFeedError(Http({err}, {url}))"),
-      Self::Deserialize(err) => write!(f, "Deserialize({err})"),
+      Self::Deserialize(err, url) => write!(f, "Deserialize({err}, {url})"),
     }
   }
 }
 
-pub async fn get_feed(
-  agency_id: u64,
-  agency_code: &str,
-  transit_workaround: bool,
-) -> Result<FeedMessage, GenFeedError> {
-  let mut entity: Vec<FeedEntity> = vec![];
-  let mut alert = get_alerts(agency_id).await?;
-  entity.append(&mut alert);
-  let schedule = get_schedule(agency_id, agency_code, transit_workaround).await?;
-  entity.append(&mut get_trip_arrivals(&schedule).await?);
-  Ok(FeedMessage {
-    header: FeedHeader {
-      gtfs_realtime_version: "2.0".to_owned(),
-      incrementality: Some(Incrementality::FullDataset.into()),
-      timestamp: Some(
-        SystemTime::now()
-          .duration_since(UNIX_EPOCH)
-          .expect("Can't get time")
-          .as_secs(),
-      ),
+/// Fetches everything TransLoc knows about an agency right now. Alerts are
+/// best-effort: if they fail we keep serving the ones from `previous`, or
+/// the failure if there aren't any.
+pub async fn build_snapshot(
+  config: &Config,
+  agency: &Agency,
+  gtfs: Arc<Gtfs>,
+  previous: Option<&Snapshot>,
+  tracker: &mut VehicleTracker,
+  history: &mut PredictionHistory,
+  cache: &mut ScheduleCache,
+) -> Result<Snapshot, GenFeedError> {
+  let (alerts, schedule) = tokio::join!(
+    get_alerts(config, agency.id),
+    get_schedule(config, agency, gtfs, cache)
+  );
+  let alerts = match alerts {
+    Ok(entity) => Ok(FeedPart {
+      entity,
+      timestamp: now(),
+    }),
+    Err(err) => {
+      log::error!("Couldn't request announcements: {err}");
+      // The last alerts we got keep the timestamp of when we got them
+      match previous.map(|previous| &previous.alerts) {
+        Some(Ok(alerts)) => Ok(alerts.clone()),
+        _ => Err(Failure::from(&err)),
+      }
+    }
+  };
+  let schedule = schedule?;
+  history.observe(&schedule.arrivals, now() as i64);
+  let traces = tracker.explain_arrivals(&schedule, now() as i64);
+  let matches = traces
+    .iter()
+    .map(|trace| ArrivalMatch::new(&schedule, trace))
+    .collect();
+  let matched = traces
+    .into_iter()
+    .filter_map(MatchTrace::into_match)
+    .collect();
+  let (trip_updates, vehicle_positions): (Vec<FeedEntity>, Vec<FeedEntity>) =
+    get_trip_arrivals(&schedule, matched, tracker, history)
+      .into_iter()
+      .partition(|entity| entity.trip_update.is_some());
+  let timestamp = now();
+  Ok(Snapshot {
+    alerts,
+    trip_updates: FeedPart {
+      entity: trip_updates,
+      timestamp,
+    },
+    vehicle_positions: FeedPart {
+      entity: vehicle_positions,
+      timestamp,
     },
-    entity,
+    crosswalk: CrosswalkReport::new(&schedule.route_crosswalk, &schedule.stop_crosswalk),
+    matches,
+    updated_at: SystemTime::now(),
   })
 }
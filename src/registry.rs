@@ -0,0 +1,177 @@
+use crate::config::{AgencyConfig, Config, ConfigError};
+use crate::crosswalk::CrosswalkFile;
+use crate::poller::Snapshots;
+use crate::protobuf_route::{request, FeedKind};
+use crate::State;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use tide::{Body, Request, Response};
+
+/// TransLoc's agencies endpoint
+#[derive(Deserialize, Debug, Default)]
+pub struct TranslocAgencies {
+  agencies: Vec<TranslocAgency>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+struct TranslocAgency {
+  id: u64,
+  name: String,
+  long_name: Option<String>,
+  short_name: Option<String>,
+}
+
+/// A configured agency, with its TransLoc id resolved.
+#[derive(Debug)]
+pub struct Agency {
+  pub id: u64,
+  /// Human-readable name, when TransLoc told us one
+  pub name: Option<String>,
+  pub settings: AgencyConfig,
+  pub crosswalk: Arc<CrosswalkFile>,
+}
+
+impl Agency {
+  pub fn code(&self) -> &str {
+    &self.settings.code
+  }
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+  Crosswalk(ConfigError),
+}
+impl Error for RegistryError {}
+impl fmt::Display for RegistryError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Crosswalk(err) => write!(f, "RegistryError(Crosswalk({err}))"),
+    }
+  }
+}
+
+/// Every agency we poll and serve, keyed by agency code.
+pub struct Registry {
+  agencies: BTreeMap<String, Arc<Agency>>,
+}
+
+impl Registry {
+  /// Builds the registry from the config. Agencies configured without an
+  /// `id` (or when `discover_agencies` is set) are looked up by code on
+  /// TransLoc's agencies endpoint. If that's down, agencies with an `id` are
+  /// served anyway and the rest are skipped.
+  pub async fn load(config: &Config) -> Result<Registry, RegistryError> {
+    let needs_discovery =
+      config.discover_agencies || config.agencies.iter().any(|agency| agency.id.is_none());
+    let discovered = if needs_discovery {
+      let url = format!("{}/agencies", config.transloc_url);
+      request::<TranslocAgencies>(&url)
+        .await
+        .unwrap_or_else(|err| {
+          log::error!("Couldn't discover agencies: {err}");
+          TranslocAgencies::default()
+        })
+    } else {
+      TranslocAgencies::default()
+    };
+    Registry::build(config, &discovered)
+  }
+
+  /// Resolves each configured agency's id against what TransLoc `discovered`.
+  pub fn build(config: &Config, discovered: &TranslocAgencies) -> Result<Registry, RegistryError> {
+    let mut agencies = BTreeMap::new();
+    for settings in &config.agencies {
+      let transloc = discovered
+        .agencies
+        .iter()
+        .find(|agency| agency.name == settings.code);
+      let id = match (settings.id, transloc) {
+        (Some(id), _) => id,
+        (None, Some(transloc)) => transloc.id,
+        (None, None) => {
+          log::error!(
+            "Agency {} has no id and TransLoc doesn't know it, skipping",
+            settings.code
+          );
+          continue;
+        }
+      };
+      let crosswalk = match &settings.crosswalk {
+        Some(path) => CrosswalkFile::load(path).map_err(RegistryError::Crosswalk)?,
+        None => CrosswalkFile::default(),
+      };
+      let agency = Agency {
+        id,
+        name: transloc.and_then(|transloc| transloc.long_name.clone()),
+        settings: settings.clone(),
+        crosswalk: Arc::new(crosswalk),
+      };
+      agencies.insert(settings.code.clone(), Arc::new(agency));
+    }
+    Ok(Registry { agencies })
+  }
+
+  pub fn get(&self, code: &str) -> Option<&Arc<Agency>> {
+    self.agencies.get(code)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Arc<Agency>> {
+    self.agencies.values()
+  }
+}
+
+#[derive(Serialize)]
+struct Index {
+  agencies: Vec<IndexEntry>,
+}
+
+#[derive(Serialize)]
+struct IndexEntry {
+  id: u64,
+  code: String,
+  name: Option<String>,
+  feeds: BTreeMap<&'static str, String>,
+  static_gtfs: String,
+  last_refresh: Option<DateTime<Utc>>,
+}
+
+fn index(config: &Config, registry: &Registry, snapshots: &Snapshots) -> Index {
+  let snapshots = snapshots.read().expect("Snapshots lock poisoned");
+  let agencies = registry
+    .iter()
+    .map(|agency| {
+      let code = agency.code();
+      let feeds = FeedKind::ALL
+        .into_iter()
+        .map(|kind| (kind.name(), format!("/rt/{code}{}", kind.path())))
+        .collect();
+      IndexEntry {
+        id: agency.id,
+        code: code.to_owned(),
+        name: agency.name.clone(),
+        feeds,
+        static_gtfs: config.gtfs_source(&agency.settings).to_string(),
+        last_refresh: snapshots
+          .get(code)
+          .and_then(|status| status.snapshot.as_ref())
+          .map(|snapshot| snapshot.updated_at.into()),
+      }
+    })
+    .collect();
+  Index { agencies }
+}
+
+pub async fn index_route(req: Request<State>) -> tide::Result {
+  let state = req.state();
+  let index = index(&state.config, &state.registry, &state.snapshots);
+  Ok(
+    Response::builder(200)
+      .body(Body::from_json(&index)?)
+      .build(),
+  )
+}
@@ -1,20 +1,22 @@
+use crate::config::Config;
+use crate::crosswalk::{CrosswalkFile, RouteCrosswalk, StopCrosswalk, TranslocRoute, TranslocStop};
+use crate::gtfs::{
+  day_time_serializer, parse_day_time, CSVFrequency, CSVRoute, CSVStop, CSVTrip, Gtfs, StopTime,
+};
 use crate::protobuf_route::request;
 use crate::protobuf_route::GenFeedError;
-use chrono::{DateTime, Duration, TimeZone, Timelike, Utc};
-use chrono_tz::{America::New_York, Tz};
+use crate::registry::Agency;
+use crate::tracker::Assignment;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use gtfs_rt::{trip_descriptor::ScheduleRelationship, TripDescriptor};
-use http_cache_reqwest::{CACacheManager, Cache, CacheMode, HttpCache};
-use lazy_static::lazy_static;
-use reqwest::Client;
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use serde::de;
-use serde::de::DeserializeOwned;
-use serde::Deserialize;
-use std::cmp;
-use std::collections::HashMap;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 // use std::future::join;
-use std::io::Cursor;
-use zip::ZipArchive;
+use std::sync::Arc;
 
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
@@ -28,17 +30,38 @@ struct Stop {
   url: String,
 }
 #[derive(Debug, Deserialize)]
-struct StopOutput {
+pub struct StopOutput {
   routes: Vec<ThinRawRoute>,
   stops: Vec<Stop>,
 }
 #[derive(Debug, Deserialize)]
-struct RouteOutput {
+pub struct RouteOutput {
   routes: Vec<RawRoute>,
   #[allow(dead_code)]
   success: bool,
 }
 
+impl StopOutput {
+  pub fn transloc_stops(&self) -> impl Iterator<Item = TranslocStop<'_>> {
+    self.stops.iter().map(|stop| TranslocStop {
+      id: stop.id,
+      code: &stop.code,
+      name: &stop.name,
+      position: stop.position,
+    })
+  }
+}
+
+impl RouteOutput {
+  pub fn transloc_routes(&self) -> impl Iterator<Item = TranslocRoute<'_>> {
+    self.routes.iter().map(|route| TranslocRoute {
+      id: route.id,
+      short_name: &route.short_name,
+      long_name: &route.long_name,
+    })
+  }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct RawRoute {
@@ -62,47 +85,22 @@ struct ThinRawRoute {
 
 #[derive(Debug)]
 struct Route {
-  long_name: String,
   id: u64,
   stops: Vec<Stop>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
-struct CSVRoute {
-  route_id: u64,
-  route_short_name: String,
-  route_long_name: String,
-  route_desc: String,
-  route_url: String,
-  route_color: String,
-  route_text_color: String,
-  route_type: u64,
-}
-
-#[derive(Debug, Deserialize, Clone)]
-pub struct StopTime {
-  pub trip_id: u64,
-  #[serde(deserialize_with = "day_time_deserializer")]
-  arrival_time: (String, u64),
-  #[allow(dead_code)]
-  #[serde(deserialize_with = "day_time_deserializer")]
-  departure_time: (String, u64),
-  pub stop_id: u64,
-  pub stop_sequence: u32,
-}
-
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 pub struct Arrival {
   agency_id: u64,
   pub call_name: String,
-  distance: f64,
-  headsign: Option<String>,
-  route_id: u64,
-  stop_id: u64,
+  /// Metres the vehicle has left to go
+  pub distance: f64,
+  pub headsign: Option<String>,
+  pub route_id: u64,
+  pub stop_id: u64,
   pub timestamp: i64,
-  trip_id: Option<u64>,
+  pub trip_id: Option<u64>,
   r#type: String,
   pub vehicle_id: u64,
 }
@@ -112,168 +110,185 @@ pub struct Arrival {
 pub struct Vehicle {
   pub id: u64,
   pub call_name: String,
-  current_stop_id: Option<u64>,
+  pub current_stop_id: Option<u64>,
   pub heading: f32,
   pub load: Option<f64>,
-  next_stop: Option<u64>,
+  pub next_stop: Option<u64>,
   off_route: bool,
   pub position: (f32, f32),
-  route_id: u64,
+  pub route_id: u64,
   segment_id: Option<u64>,
   pub speed: f32,
-  stop_pattern_id: u64,
+  pub stop_pattern_id: u64,
   pub timestamp: u64,
   trip_id: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
-struct VehicleStatuses {
+pub struct VehicleStatuses {
   arrivals: Vec<Arrival>,
   vehicles: Vec<Vehicle>,
 }
 
-fn get_time_component<'de, D>(component: &str) -> Result<u64, D::Error>
-where
-  D: de::Deserializer<'de>,
-{
-  component.parse::<u64>().map_err(|err| {
-    de::Error::custom(format!(
-      "Failed to deserialize day_time: {} {}",
-      component, err
-    ))
-  })
-}
-
-fn day_time_deserializer<'de, D>(deserializer: D) -> Result<(String, u64), D::Error>
-where
-  D: de::Deserializer<'de>,
-{
-  let time: String = Deserialize::deserialize(deserializer)?;
-  let k = time.clone();
-  let time_parts: Vec<&str> = k.splitn(3, ':').collect();
-  let hour = get_time_component::<D>(time_parts[0])?;
-  let minute = get_time_component::<D>(time_parts[1])?;
-  let second = get_time_component::<D>(time_parts[2])?;
-  // hour/minute/second to seconds:
-  let value = hour * 3600 + minute * 60 + second;
-  Ok((time, value))
-}
-
-pub fn day_time_serializer(total_seconds: u64) -> String {
-  let seconds = total_seconds % 60;
-  let total_minutes = total_seconds / 60;
-  let minutes = total_minutes % 60;
-  let hours = total_minutes / 60;
-  format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
-}
-
-fn read_csv<T: DeserializeOwned>(
-  zip: &mut ZipArchive<Cursor<Vec<u8>>>,
-  path: &str,
-) -> Result<Vec<T>, GenFeedError> {
-  let file = zip.by_name(path).map_err(GenFeedError::Zip)?;
-  let mut reader = csv::Reader::from_reader(file);
-  let reader = reader.deserialize();
-  Ok(reader.filter_map(|item| item.ok()).collect::<Vec<T>>())
+/// Per-agency settings for joining TransLoc onto the static schedule
+#[derive(Debug, Clone)]
+pub struct MatchSettings {
+  pub timezone: Tz,
+  pub service_day_cutoff: u32,
+  pub match_ambiguity: f64,
+  pub stop_match_distance: f64,
+  pub crosswalk: Arc<CrosswalkFile>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
-struct CSVTrip {
-  trip_id: u64,
-  route_id: u64,
-  service_id: u64,
-  trip_headsign: String,
-  trip_short_name: String,
-  direction_id: u64,
-  shape_id: String,
-  wheelchair_accessible: u64,
-  bikes_allowed: u64,
-  block_id: String,
-  block_name: String,
+impl MatchSettings {
+  pub fn new(config: &Config, agency: &Agency, timezone: Tz) -> MatchSettings {
+    MatchSettings {
+      timezone,
+      service_day_cutoff: config.service_day_cutoff(&agency.settings),
+      match_ambiguity: config.match_ambiguity(&agency.settings),
+      stop_match_distance: config.stop_match_distance(&agency.settings),
+      crosswalk: agency.crosswalk.clone(),
+    }
+  }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct CSVFrequency {
-  trip_id: u64,
-  #[serde(deserialize_with = "day_time_deserializer")]
-  start_time: (String, u64),
-  #[serde(deserialize_with = "day_time_deserializer")]
-  end_time: (String, u64),
-  headway_secs: u64,
-  #[allow(dead_code)]
-  exact_times: u8,
+/// A crosswalk, and what it was built from
+struct Cached<T> {
+  gtfs: Arc<Gtfs>,
+  overrides: Arc<CrosswalkFile>,
+  /// Hash of the TransLoc side
+  transloc: u64,
+  crosswalk: Arc<T>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize, Clone)]
-pub struct CSVStop {
-  stop_id: u64,
-  stop_code: String,
-  stop_name: String,
-  stop_desc: String,
-  stop_lat: f64,
-  stop_lon: f64,
-  stop_url: String,
-  location_type: u64,
+/// `slot`'s crosswalk if it was built from the same inputs, or else a new one
+/// from `build`
+fn cached<T>(
+  slot: &mut Option<Cached<T>>,
+  gtfs: &Arc<Gtfs>,
+  overrides: &Arc<CrosswalkFile>,
+  transloc: u64,
+  build: impl FnOnce() -> T,
+) -> Arc<T> {
+  if let Some(cached) = slot {
+    if Arc::ptr_eq(&cached.gtfs, gtfs)
+      && Arc::ptr_eq(&cached.overrides, overrides)
+      && cached.transloc == transloc
+    {
+      return cached.crosswalk.clone();
+    }
+  }
+  let crosswalk = Arc::new(build());
+  *slot = Some(Cached {
+    gtfs: gtfs.clone(),
+    overrides: overrides.clone(),
+    transloc,
+    crosswalk: crosswalk.clone(),
+  });
+  crosswalk
+}
+
+/// What one agency's polls build from its static GTFS and TransLoc's routes
+/// and stops, kept until one of them changes. Those rarely do, but matching
+/// every TransLoc stop against every GTFS one isn't cheap.
+#[derive(Default)]
+pub struct ScheduleCache {
+  /// The timezone worked out for a GTFS, so a missing one is only warned
+  /// about once per load
+  timezone: Option<(Arc<Gtfs>, Tz)>,
+  routes: Option<Cached<RouteCrosswalk>>,
+  stops: Option<Cached<StopCrosswalk>>,
+}
+
+impl ScheduleCache {
+  fn timezone(&mut self, config: &Config, agency: &Agency, gtfs: &Arc<Gtfs>) -> Tz {
+    match &self.timezone {
+      Some((cached, timezone)) if Arc::ptr_eq(cached, gtfs) => *timezone,
+      _ => {
+        let timezone = config.timezone(&agency.settings, gtfs);
+        self.timezone = Some((gtfs.clone(), timezone));
+        timezone
+      }
+    }
+  }
+
+  fn route_crosswalk(
+    &mut self,
+    routes: &RouteOutput,
+    gtfs: &Arc<Gtfs>,
+    settings: &MatchSettings,
+  ) -> Arc<RouteCrosswalk> {
+    let mut hasher = DefaultHasher::new();
+    for route in routes.transloc_routes() {
+      (route.id, route.short_name, route.long_name).hash(&mut hasher);
+    }
+    cached(
+      &mut self.routes,
+      gtfs,
+      &settings.crosswalk,
+      hasher.finish(),
+      || RouteCrosswalk::new(routes.transloc_routes(), gtfs, &settings.crosswalk),
+    )
+  }
+
+  fn stop_crosswalk(
+    &mut self,
+    stops: &StopOutput,
+    gtfs: &Arc<Gtfs>,
+    settings: &MatchSettings,
+  ) -> Arc<StopCrosswalk> {
+    let mut hasher = DefaultHasher::new();
+    settings.stop_match_distance.to_bits().hash(&mut hasher);
+    for stop in stops.transloc_stops() {
+      let position = stop.position.map(f64::to_bits);
+      (stop.id, stop.code, stop.name, position).hash(&mut hasher);
+    }
+    cached(
+      &mut self.stops,
+      gtfs,
+      &settings.crosswalk,
+      hasher.finish(),
+      || {
+        StopCrosswalk::new(
+          stops.transloc_stops(),
+          gtfs,
+          &settings.crosswalk,
+          settings.stop_match_distance,
+        )
+      },
+    )
+  }
 }
 
 pub struct Schedule {
-  // zip: ZipArchive<Cursor<Vec<u8>>>,
+  gtfs: Arc<Gtfs>,
   routes: HashMap<u64, Route>,
-  csv_routes: HashMap<String, CSVRoute>,
-  csv_stop_times: Vec<StopTime>,
-  csv_trips: Vec<CSVTrip>,
-  csv_stops: HashMap<String, CSVStop>,
-  csv_frequencies: HashMap<u64, CSVFrequency>,
+  pub route_crosswalk: Arc<RouteCrosswalk>,
+  pub stop_crosswalk: Arc<StopCrosswalk>,
   pub arrivals: Vec<Arrival>,
+  /// vehicle_id -> (TransLoc route_id, TransLoc stop_id, GTFS stop_id,
+  /// timestamp) of each of its arrivals with a GTFS stop, so matching one
+  /// arrival doesn't have to look through everyone else's
+  vehicle_stops: HashMap<u64, Vec<(u64, u64, u64, i64)>>,
   pub vehicles: HashMap<u64, Vehicle>,
-  transit_workaround: bool,
+  pub timezone: Tz,
+  service_day_cutoff: u32,
+  match_ambiguity: f64,
   // stops: HashMap<u64, Stop>,
 }
 
-lazy_static! {
-  static ref CACHING_HTTP: ClientWithMiddleware = ClientBuilder::new(Client::new())
-    .with(Cache(HttpCache {
-      mode: CacheMode::Default,
-      manager: CACacheManager::default(),
-      options: None,
-    }))
-    .build();
-}
-
 pub async fn get_schedule(
-  agency_id: u64,
-  agency_code: &str,
-  transit_workaround: bool,
+  config: &Config,
+  agency: &Agency,
+  gtfs: Arc<Gtfs>,
+  cache: &mut ScheduleCache,
 ) -> Result<Schedule, GenFeedError> {
-  let bytes = CACHING_HTTP
-    .get("https://api.transloc.com/gtfs/rit.zip")
-    .send()
-    .await
-    .map_err(|err| {
-      GenFeedError::ZipHttp(
-        err,
-        format!("https://api.transloc.com/gtfs/{agency_code}.zip"),
-      )
-    })?
-    .bytes()
-    .await
-    .map_err(|err| {
-      GenFeedError::Http(
-        err,
-        format!("https://api.transloc.com/gtfs/{agency_code}.zip"),
-      )
-    })?;
-  let bytes = Vec::from(bytes);
-  let mut zip = ZipArchive::new(Cursor::new(bytes)).map_err(GenFeedError::Zip)?;
-
-  let stops =
-    format!("https://feeds.transloc.com/3/stops?include_routes=true&agencies={agency_id}");
-  let routes = format!("https://feeds.transloc.com/3/routes?agencies={agency_id}");
-  let vehicle_statuses = format!(
-    "https://feeds.transloc.com/3/vehicle_statuses?agencies={agency_id}&include_arrivals=true"
-  );
+  let agency_id = agency.id;
+  let transloc_url = &config.transloc_url;
+  let stops = format!("{transloc_url}/stops?include_routes=true&agencies={agency_id}");
+  let routes = format!("{transloc_url}/routes?agencies={agency_id}");
+  let vehicle_statuses =
+    format!("{transloc_url}/vehicle_statuses?agencies={agency_id}&include_arrivals=true");
   let (stops, routes, vehicle_statuses) = tokio::join!(
     request::<StopOutput>(&stops),
     request::<RouteOutput>(&routes),
@@ -283,94 +298,102 @@ pub async fn get_schedule(
   let routes = routes?;
   let vehicle_statuses = vehicle_statuses?;
 
-  let routes = routes
-    .routes
-    .into_iter()
-    .map(|route| {
-      let thin_route = stops
-        .routes
-        .iter()
-        .find(|other| other.id == route.id)
-        .unwrap_or_else(|| panic!("Route {} doesn't exist on /stops?", route.id));
-      let stops = stops
-        .stops
-        .iter()
-        .filter(|stop| thin_route.stops.contains(&stop.id))
-        .cloned()
-        .collect();
-      Route {
-        long_name: route.long_name,
-        id: route.id,
-        stops,
-      }
-    })
-    .map(|route| (route.id, route));
-  let routes = HashMap::from_iter(routes);
-
-  let csv_routes: Vec<CSVRoute> = read_csv(&mut zip, "routes.txt")?;
-  let csv_stop_times: Vec<StopTime> = read_csv(&mut zip, "stop_times.txt")?;
-  let csv_trips: Vec<CSVTrip> = read_csv(&mut zip, "trips.txt")?;
-  let csv_routes = HashMap::from_iter(
-    csv_routes
-      .into_iter()
-      .map(|route| (route.route_long_name.clone(), route)),
-  );
-  let csv_stops: Vec<CSVStop> = read_csv(&mut zip, "stops.txt")?;
-  let csv_stops = HashMap::from_iter(
-    csv_stops
-      .into_iter()
-      .map(|stop| (stop.stop_code.clone(), stop)),
-  );
-  let csv_frequencies: Vec<CSVFrequency> = read_csv(&mut zip, "frequencies.txt")?;
-  let csv_frequencies = HashMap::from_iter(
-    csv_frequencies
-      .into_iter()
-      .map(|frequency| (frequency.trip_id, frequency)),
-  );
-  let vehicles = HashMap::from_iter(
-    vehicle_statuses
-      .vehicles
-      .into_iter()
-      .map(|vehicle| (vehicle.id, vehicle)),
-  );
-
-  Ok(Schedule {
+  let timezone = cache.timezone(config, agency, &gtfs);
+  let settings = MatchSettings::new(config, agency, timezone);
+  Ok(Schedule::new(
+    gtfs,
+    stops,
     routes,
-    csv_routes,
-    csv_stop_times,
-    csv_frequencies,
-    csv_stops,
-    csv_trips,
-    arrivals: vehicle_statuses.arrivals,
-    vehicles,
-    transit_workaround,
-  })
-}
-
-fn nearby(real_time: u64, seconds: (String, u64)) -> bool {
-  let seconds = seconds.1;
+    vehicle_statuses,
+    settings,
+    cache,
+  ))
+}
+
+/// How far a scheduled arrival can be from the real one and still count
+const NEARBY_SECS: u64 = 60 * 10;
+
+fn nearby(real_time: u64, seconds: u64) -> bool {
   let delta = (real_time as i64) - (seconds as i64);
-  delta < 60 * 10 && delta > -60 * 10
+  delta < NEARBY_SECS as i64 && delta > -(NEARBY_SECS as i64)
 }
 
-pub fn get_arrival_time(arrival: &Arrival) -> (DateTime<Tz>, u64) {
-  let date = Utc
-    .timestamp_opt(arrival.timestamp, 0)
-    .single()
-    .expect("Invalid arrival timestamp?");
-  let date = date.with_timezone(&New_York);
+/// A moment expressed the way GTFS does: a service day, and the number of
+/// seconds since that day's "noon minus 12h" (which can run past 24:00:00).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServiceTime {
+  pub date: NaiveDate,
+  pub secs: u64,
+}
 
-  let secs: u64 = date.num_seconds_from_midnight().into();
-  if date.hour() < 4 {
-    // We want to look at yesterday!
-    (date, secs + Duration::days(1).num_seconds() as u64)
-  } else {
-    (date, secs)
+impl ServiceTime {
+  /// The instant the service day starts. GTFS defines it as noon minus 12h
+  /// so DST changes don't shift the day's schedule.
+  fn day_start(date: NaiveDate, timezone: Tz) -> Option<DateTime<Tz>> {
+    let noon = date.and_hms_opt(12, 0, 0)?;
+    let noon = timezone.from_local_datetime(&noon).single()?;
+    Some(noon - Duration::hours(12))
+  }
+
+  pub fn new(date: NaiveDate, moment: DateTime<Tz>) -> Option<ServiceTime> {
+    let start = Self::day_start(date, moment.timezone())?;
+    let secs = (moment - start).num_seconds();
+    Some(ServiceTime {
+      date,
+      secs: u64::try_from(secs).ok()?,
+    })
+  }
+
+  /// `start_date` for a `TripDescriptor`
+  pub fn start_date(&self) -> String {
+    self.date.format("%Y%m%d").to_string()
   }
 }
 
-fn within_buffer(start_secs: u64, now: u64, end_secs: u64) -> bool {
-  (start_secs - 60 * 10) < now && now < (end_secs + 60 * 10)
+/// The service days a timestamp could belong to, most likely first. Before
+/// `cutoff_hour` we're probably still running the previous day's late-night
+/// trips (times past 24:00:00), but today's early trips are still possible.
+pub fn service_times(timestamp: i64, timezone: Tz, cutoff_hour: u32) -> Vec<ServiceTime> {
+  let moment = match Utc.timestamp_opt(timestamp, 0).single() {
+    Some(moment) => moment.with_timezone(&timezone),
+    None => {
+      log::warn!("Skipping arrival with invalid timestamp {timestamp}");
+      return vec![];
+    }
+  };
+  let today = moment.date_naive();
+  let dates = if moment.hour() < cutoff_hour {
+    vec![today - Duration::days(1), today]
+  } else {
+    vec![today]
+  };
+  dates
+    .into_iter()
+    .filter_map(|date| ServiceTime::new(date, moment))
+    .collect()
+}
+
+/// Start times of the frequency-based runs that would have left just before
+/// and just after `start_time`, in whichever of the trip's headway windows
+/// they fall in, along with that window.
+fn frequency_starts(frequencies: &[CSVFrequency], start_time: i64) -> Vec<(u64, &CSVFrequency)> {
+  let mut starts = vec![];
+  for frequency in frequencies {
+    if frequency.headway_secs == 0 {
+      continue;
+    }
+    let (first, last) = (frequency.start_time.1, frequency.end_time.1);
+    let headway = frequency.headway_secs as i64;
+    let runs = (start_time - first as i64).div_euclid(headway);
+    starts.extend(
+      (runs..=runs + 1)
+        .filter(|&run| run >= 0)
+        .map(|run| first + run as u64 * frequency.headway_secs)
+        .filter(|&start| start < last)
+        .map(|start| (start, frequency)),
+    );
+  }
+  starts
 }
 
 pub struct ArrivalData {
@@ -378,91 +401,683 @@ pub struct ArrivalData {
   pub trip_descriptor: TripDescriptor,
   pub stop_time: StopTime,
   pub scheduled_arrival: u64,
+  /// When the arrival happens, on the service day of the matched trip
+  pub service_time: ServiceTime,
   pub csv_stop: CSVStop,
   pub frequency: Option<CSVFrequency>,
 }
 
+/// How an arrival's trip was picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchReason {
+  /// TransLoc's own trip_id, looked up directly
+  TranslocTripId,
+  /// The vehicle was already on it
+  Tracked,
+  BestScore,
+}
+
+/// The step an arrival's match failed at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchFailure {
+  /// The TransLoc route isn't joined to a GTFS one
+  Route,
+  /// The TransLoc stop isn't on the route, or isn't joined to a GTFS stop
+  Stop,
+  /// No trip on the route serves the stop around the arrival's time
+  NoTripInWindow,
+  /// The best two trips score too close to call
+  Ambiguous,
+}
+
+/// Everything matching one arrival considered.
+pub struct MatchTrace {
+  pub arrival: Arrival,
+  /// Every trip instance and stop visit the arrival could be, best first
+  pub candidates: Vec<(f64, ArrivalData)>,
+  /// The index of the chosen candidate
+  pub outcome: Result<(usize, MatchReason), MatchFailure>,
+}
+
+impl MatchTrace {
+  pub fn into_match(mut self) -> Option<ArrivalData> {
+    let (index, _) = self.outcome.ok()?;
+    Some(self.candidates.swap_remove(index).1)
+  }
+}
+
 impl Schedule {
+  /// Joins the TransLoc responses from one poll onto the static schedule,
+  /// reusing `cache`'s crosswalks when nothing they depend on changed.
+  pub fn new(
+    gtfs: Arc<Gtfs>,
+    stops: StopOutput,
+    routes: RouteOutput,
+    vehicle_statuses: VehicleStatuses,
+    settings: MatchSettings,
+    cache: &mut ScheduleCache,
+  ) -> Schedule {
+    let route_crosswalk = cache.route_crosswalk(&routes, &gtfs, &settings);
+    let stop_crosswalk = cache.stop_crosswalk(&stops, &gtfs, &settings);
+    let routes = routes
+      .routes
+      .into_iter()
+      .filter_map(|route| {
+        // The two endpoints aren't fetched atomically, so they can disagree
+        let thin_route = match stops.routes.iter().find(|other| other.id == route.id) {
+          Some(thin_route) => thin_route,
+          None => {
+            log::warn!("Skipping route {}, which isn't on /stops", route.id);
+            return None;
+          }
+        };
+        let stops = stops
+          .stops
+          .iter()
+          .filter(|stop| thin_route.stops.contains(&stop.id))
+          .cloned()
+          .collect();
+        Some(Route {
+          id: route.id,
+          stops,
+        })
+      })
+      .map(|route| (route.id, route));
+    let routes = HashMap::from_iter(routes);
+
+    let vehicles = HashMap::from_iter(
+      vehicle_statuses
+        .vehicles
+        .into_iter()
+        .map(|vehicle| (vehicle.id, vehicle)),
+    );
+
+    let mut schedule = Schedule {
+      gtfs,
+      routes,
+      route_crosswalk,
+      stop_crosswalk,
+      arrivals: vehicle_statuses.arrivals,
+      vehicle_stops: HashMap::new(),
+      vehicles,
+      timezone: settings.timezone,
+      service_day_cutoff: settings.service_day_cutoff,
+      match_ambiguity: settings.match_ambiguity,
+    };
+    let mut vehicle_stops: HashMap<u64, Vec<(u64, u64, u64, i64)>> = HashMap::new();
+    for arrival in &schedule.arrivals {
+      let csv_stop = schedule
+        .routes
+        .get(&arrival.route_id)
+        .and_then(|route| schedule.csv_stop(route, arrival.stop_id));
+      if let Some(csv_stop) = csv_stop {
+        vehicle_stops.entry(arrival.vehicle_id).or_default().push((
+          arrival.route_id,
+          arrival.stop_id,
+          csv_stop.stop_id,
+          arrival.timestamp,
+        ));
+      }
+    }
+    schedule.vehicle_stops = vehicle_stops;
+    schedule
+  }
+
+  /// The trip TransLoc says the arrival is on. The vehicle's trip only
+  /// covers its next stop, since later arrivals can be on its next trip.
+  fn transloc_trip_id(&self, arrival: &Arrival) -> Option<u64> {
+    arrival.trip_id.or_else(|| {
+      self
+        .vehicles
+        .get(&arrival.vehicle_id)
+        .filter(|vehicle| vehicle.next_stop == Some(arrival.stop_id))
+        .and_then(|vehicle| vehicle.trip_id)
+    })
+  }
+
+  /// The GTFS stop a TransLoc stop on `route` corresponds to
+  fn csv_stop(&self, route: &Route, stop_id: u64) -> Option<&CSVStop> {
+    let stop = route.stops.iter().find(|stop| stop.id == stop_id)?;
+    let stop_match = self.stop_crosswalk.get(stop.id)?;
+    self.gtfs.stops.get(&stop_match.stop_id)
+  }
+
+  /// The stop_sequence of `trip_id`'s last visit to GTFS stop `stop_id` no
+  /// later than `stop_sequence`
+  pub fn visit_at_or_before(&self, trip_id: u64, stop_id: u64, stop_sequence: u32) -> Option<u32> {
+    self
+      .gtfs
+      .stop_times_at(trip_id, stop_id)
+      .map(|stop_time| stop_time.stop_sequence)
+      .filter(|&sequence| sequence <= stop_sequence)
+      .max()
+  }
+
+  /// Matches an arrival with nothing to go on but the poll itself.
   pub fn find_trip_id(&self, arrival: &Arrival) -> Option<ArrivalData> {
-    let route = self.routes.get(&arrival.route_id)?;
-    let csv_route = self.csv_routes.get(&route.long_name)?;
-    let stop = route.stops.iter().find(|stop| stop.id == arrival.stop_id)?;
-    let csv_stop = self.csv_stops.get(&stop.code)?;
-    let arrival_time = get_arrival_time(arrival).1;
-
-    for trip in &self.csv_trips {
-      if csv_route.route_id == trip.route_id {
-        if let Some(frequency) = self.csv_frequencies.get(&trip.trip_id) {
-          for stop_time in &self.csv_stop_times {
-            if stop_time.trip_id != trip.trip_id {
-              continue;
-            }
+    self.match_arrival(arrival, None, None)
+  }
 
-            if !within_buffer(frequency.start_time.1, arrival_time, frequency.end_time.1) {
-              continue;
-            }
-            let trip_iteration: f64 = (arrival_time as i64 - stop_time.arrival_time.1 as i64)
-              as f64
-              / frequency.headway_secs as f64;
-            let trip_iteration = cmp::max(trip_iteration.round() as u64, 0);
-            let start_time = frequency.headway_secs * trip_iteration + frequency.start_time.1;
-            let scheduled_arrival =
-              frequency.headway_secs * trip_iteration + stop_time.arrival_time.1;
-            return Some(ArrivalData {
-              arrival: arrival.clone(),
-              trip_descriptor: TripDescriptor {
-                trip_id: Some(if self.transit_workaround {
-                  format!("{}_{}", trip.trip_id, start_time)
-                } else {
-                  trip.trip_id.to_string()
-                }),
-                route_id: Some(trip.route_id.to_string()),
-                direction_id: None,
-                start_time: if self.transit_workaround {
-                  None
-                } else {
-                  Some(day_time_serializer(start_time))
-                },
-                start_date: None,
-                schedule_relationship: Some(ScheduleRelationship::Scheduled.into()),
-              },
-              stop_time: stop_time.clone(),
-              scheduled_arrival,
-              csv_stop: csv_stop.clone(),
-              frequency: Some(frequency.clone()),
-            });
-          }
-        } else {
-          for stop_time in &self.csv_stop_times {
-            if stop_time.trip_id != trip.trip_id {
+  /// Scores every trip instance the arrival could belong to and picks the
+  /// best, unless the runner-up is too close to call. A vehicle stays on its
+  /// `current` trip as long as the arrival is further along it and no other
+  /// trip fits much better. `pattern_direction` is the direction the
+  /// vehicle's TransLoc stop pattern has run in before.
+  pub fn match_arrival(
+    &self,
+    arrival: &Arrival,
+    current: Option<&Assignment>,
+    pattern_direction: Option<u64>,
+  ) -> Option<ArrivalData> {
+    self
+      .explain_arrival(arrival, current, pattern_direction)
+      .into_match()
+  }
+
+  /// `match_arrival`, keeping every candidate and where it stopped.
+  pub fn explain_arrival(
+    &self,
+    arrival: &Arrival,
+    current: Option<&Assignment>,
+    pattern_direction: Option<u64>,
+  ) -> MatchTrace {
+    let mut trace = MatchTrace {
+      arrival: arrival.clone(),
+      candidates: vec![],
+      outcome: Err(MatchFailure::Route),
+    };
+    let route = match self.routes.get(&arrival.route_id) {
+      Some(route) => route,
+      None => return trace,
+    };
+    let csv_route = match self
+      .route_crosswalk
+      .get(arrival.route_id)
+      .and_then(|route_match| self.gtfs.routes.get(&route_match.route_id))
+    {
+      Some(csv_route) => csv_route,
+      None => return trace,
+    };
+    let csv_stop = match self.csv_stop(route, arrival.stop_id) {
+      Some(csv_stop) => csv_stop,
+      None => {
+        trace.outcome = Err(MatchFailure::Stop);
+        return trace;
+      }
+    };
+    let context = MatchContext::new(self, arrival, csv_route, pattern_direction);
+
+    let service_times = service_times(arrival.timestamp, self.timezone, self.service_day_cutoff);
+    let mut candidates: Vec<ArrivalData> = service_times
+      .iter()
+      .flat_map(|service_time| self.candidates_on(arrival, csv_route, csv_stop, *service_time))
+      .collect();
+    if let Some(current) = current {
+      // However late it's running, the vehicle's own trip is a candidate, so
+      // only REASSIGN_MARGIN can move it off
+      let tracked = self.tracked_candidates(arrival, csv_route, csv_stop, current);
+      add_candidates(&mut candidates, tracked);
+    }
+    let mut hinted_trip_id = self.transloc_trip_id(arrival);
+    if let Some(trip_id) = hinted_trip_id {
+      if self.gtfs.frequencies.contains_key(&trip_id) {
+        // Every run of a frequency-based trip shares its id, so it only
+        // narrows down the candidates and can't settle which run this is
+        if candidates
+          .iter()
+          .any(|candidate| candidate.stop_time.trip_id == trip_id)
+        {
+          candidates.retain(|candidate| candidate.stop_time.trip_id == trip_id);
+        }
+        hinted_trip_id = None;
+      } else {
+        // However late it's running, the trip TransLoc names is a candidate
+        let hinted = self.hinted_candidates(arrival, csv_route, csv_stop, trip_id, &service_times);
+        add_candidates(&mut candidates, hinted);
+      }
+    }
+    let mut candidates: Vec<(f64, ArrivalData)> = candidates
+      .into_iter()
+      .map(|candidate| (context.score(self, &candidate), candidate))
+      .collect();
+    candidates.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    trace.outcome = self.pick(arrival, hinted_trip_id, &candidates, current);
+    trace.candidates = candidates;
+    trace
+  }
+
+  /// Which of the sorted `candidates` the arrival belongs to, and why.
+  fn pick(
+    &self,
+    arrival: &Arrival,
+    hinted_trip_id: Option<u64>,
+    candidates: &[(f64, ArrivalData)],
+    current: Option<&Assignment>,
+  ) -> Result<(usize, MatchReason), MatchFailure> {
+    // Agencies that publish their GTFS through TransLoc share trip ids with
+    // it. Frequency-based trips have already been narrowed down to theirs.
+    if let Some(trip_id) = hinted_trip_id {
+      let hinted = candidates
+        .iter()
+        .position(|(_, candidate)| candidate.stop_time.trip_id == trip_id);
+      if let Some(index) = hinted {
+        return Ok((index, MatchReason::TranslocTripId));
+      }
+    }
+
+    if let Some(current) = current {
+      let best_score = candidates.first().map_or(0.0, |(score, _)| *score);
+      let current_id = trip_instance_id(&current.trip);
+      let still_on_trip = candidates.iter().position(|(score, candidate)| {
+        trip_instance_id(&candidate.trip_descriptor) == current_id
+          && candidate.stop_time.stop_sequence >= current.stop_sequence
+          && score - best_score < REASSIGN_MARGIN
+      });
+      if let Some(index) = still_on_trip {
+        return Ok((index, MatchReason::Tracked));
+      }
+    }
+
+    // A loop visits some stops twice; only the better visit competes
+    let best_id = match candidates.first() {
+      Some((_, best)) => trip_instance_id(&best.trip_descriptor),
+      None => {
+        log::debug!(
+          "No trip for vehicle {} at stop {} around {}",
+          arrival.vehicle_id,
+          arrival.stop_id,
+          arrival.timestamp
+        );
+        return Err(MatchFailure::NoTripInWindow);
+      }
+    };
+    let best_score = candidates[0].0;
+    let runner_up = candidates
+      .iter()
+      .map(|(score, candidate)| (score, trip_instance_id(&candidate.trip_descriptor)))
+      .find(|(_, instance_id)| *instance_id != best_id);
+    if let Some((runner_up_score, runner_up_id)) = runner_up {
+      if runner_up_score - best_score < self.match_ambiguity {
+        log::debug!(
+          "Arrival of {} at {} is ambiguous between {best_id} ({best_score:.2}) and {runner_up_id} ({runner_up_score:.2})",
+          arrival.vehicle_id,
+          arrival.stop_id,
+        );
+        return Err(MatchFailure::Ambiguous);
+      }
+    }
+    Ok((0, MatchReason::BestScore))
+  }
+
+  /// Every trip instance on `service_time`'s day that plausibly serves this
+  /// stop around then.
+  fn candidates_on(
+    &self,
+    arrival: &Arrival,
+    csv_route: &CSVRoute,
+    csv_stop: &CSVStop,
+    service_time: ServiceTime,
+  ) -> Vec<ArrivalData> {
+    let arrival_time = service_time.secs;
+    let mut candidates = vec![];
+    let is_active = |trip: &CSVTrip| {
+      self
+        .gtfs
+        .is_service_active(trip.service_id, service_time.date)
+    };
+    for trip in self.gtfs.frequency_trips_for_route(csv_route.route_id) {
+      if !is_active(trip) {
+        continue;
+      }
+      if let Some(frequencies) = self.gtfs.frequencies.get(&trip.trip_id) {
+        let first_arrival = match self.gtfs.stop_times(trip.trip_id).first() {
+          Some(stop_time) => stop_time.arrival_time.1,
+          None => continue,
+        };
+        // A loop can visit the stop more than once per run; each visit is
+        // its own candidate
+        for stop_time in self.gtfs.stop_times_at(trip.trip_id, csv_stop.stop_id) {
+          // A stop_time before the trip's first is a broken feed
+          let offset = match stop_time.arrival_time.1.checked_sub(first_arrival) {
+            Some(offset) => offset,
+            None => continue,
+          };
+          let start_time = arrival_time as i64 - offset as i64;
+          for (start_time, frequency) in frequency_starts(frequencies, start_time) {
+            if !nearby(arrival_time, start_time + offset) {
               continue;
             }
-            if stop_time.trip_id == trip.trip_id
-              && stop_time.stop_id == csv_stop.stop_id
-              && nearby(arrival_time, stop_time.arrival_time.clone())
-            {
-              // trip and stop_time belong to us!
-              return Some(ArrivalData {
-                arrival: arrival.clone(),
-                trip_descriptor: TripDescriptor {
-                  trip_id: Some(trip.trip_id.to_string()),
-                  route_id: Some(trip.route_id.to_string()),
-                  direction_id: None,
-                  start_time: None,
-                  start_date: None,
-                  schedule_relationship: None,
-                },
-                stop_time: stop_time.clone(),
-                scheduled_arrival: stop_time.arrival_time.1,
-                csv_stop: csv_stop.clone(),
-                frequency: None,
-              });
-            }
+            candidates.push(frequency_candidate(
+              arrival,
+              trip,
+              stop_time,
+              csv_stop,
+              service_time,
+              (start_time, frequency),
+              offset,
+            ));
           }
         }
       }
     }
-    eprintln!("Missing trip?! {:?} Stop={:?}", arrival, csv_stop);
-    None
+    let stop_times = self.gtfs.stop_times_between(
+      csv_route.route_id,
+      csv_stop.stop_id,
+      arrival_time.saturating_sub(NEARBY_SECS),
+      arrival_time + NEARBY_SECS,
+    );
+    for stop_time in stop_times {
+      if !nearby(arrival_time, stop_time.arrival_time.1) {
+        continue;
+      }
+      match self.gtfs.trips.get(&stop_time.trip_id) {
+        Some(trip) if is_active(trip) => candidates.push(scheduled_candidate(
+          arrival,
+          trip,
+          stop_time,
+          csv_stop,
+          service_time,
+        )),
+        _ => {}
+      }
+    }
+    candidates
+  }
+
+  /// Every visit to the stop by the trip instance the vehicle is `current`ly
+  /// on, however far from the arrival's time.
+  fn tracked_candidates(
+    &self,
+    arrival: &Arrival,
+    csv_route: &CSVRoute,
+    csv_stop: &CSVStop,
+    current: &Assignment,
+  ) -> Vec<ArrivalData> {
+    let trip = current
+      .trip
+      .trip_id
+      .as_deref()
+      .and_then(|trip_id| trip_id.parse::<u64>().ok())
+      .and_then(|trip_id| self.gtfs.trips.get(&trip_id));
+    let trip = match trip {
+      Some(trip) if trip.route_id == csv_route.route_id => trip,
+      _ => return vec![],
+    };
+    let date = current
+      .trip
+      .start_date
+      .as_deref()
+      .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok());
+    let moment = Utc
+      .timestamp_opt(arrival.timestamp, 0)
+      .single()
+      .map(|moment| moment.with_timezone(&self.timezone));
+    let service_time = match date
+      .zip(moment)
+      .and_then(|(date, moment)| ServiceTime::new(date, moment))
+    {
+      Some(service_time) => service_time,
+      None => return vec![],
+    };
+    let stop_times = self.gtfs.stop_times_at(trip.trip_id, csv_stop.stop_id);
+
+    let frequencies = match self.gtfs.frequencies.get(&trip.trip_id) {
+      Some(frequencies) => frequencies,
+      None => {
+        return stop_times
+          .map(|stop_time| scheduled_candidate(arrival, trip, stop_time, csv_stop, service_time))
+          .collect()
+      }
+    };
+    let start_time = current.trip.start_time.as_deref().and_then(parse_day_time);
+    let run = start_time.and_then(|start_time| {
+      let frequency = frequencies.iter().find(|frequency| {
+        frequency.start_time.1 <= start_time && start_time < frequency.end_time.1
+      })?;
+      Some((start_time, frequency))
+    });
+    let first_arrival = self
+      .gtfs
+      .stop_times(trip.trip_id)
+      .first()
+      .map(|stop_time| stop_time.arrival_time.1);
+    let (run, first_arrival) = match run.zip(first_arrival) {
+      Some(found) => found,
+      None => return vec![],
+    };
+    stop_times
+      .filter_map(|stop_time| {
+        let offset = stop_time.arrival_time.1.checked_sub(first_arrival)?;
+        Some(frequency_candidate(
+          arrival,
+          trip,
+          stop_time,
+          csv_stop,
+          service_time,
+          run,
+          offset,
+        ))
+      })
+      .collect()
+  }
+
+  /// Every visit to the stop by GTFS trip `trip_id` on the days it runs,
+  /// however far from the arrival's time. Frequency-based trips have no
+  /// single run to look up, so they only come from `candidates_on`. A trip
+  /// on another route is an id collision or a leftover from an interlined
+  /// run, so it's no help either.
+  fn hinted_candidates(
+    &self,
+    arrival: &Arrival,
+    csv_route: &CSVRoute,
+    csv_stop: &CSVStop,
+    trip_id: u64,
+    service_times: &[ServiceTime],
+  ) -> Vec<ArrivalData> {
+    let trip = match self.gtfs.trips.get(&trip_id) {
+      Some(trip)
+        if trip.route_id == csv_route.route_id && !self.gtfs.frequencies.contains_key(&trip_id) =>
+      {
+        trip
+      }
+      _ => return vec![],
+    };
+    service_times
+      .iter()
+      .filter(|service_time| {
+        self
+          .gtfs
+          .is_service_active(trip.service_id, service_time.date)
+      })
+      .flat_map(|service_time| {
+        self
+          .gtfs
+          .stop_times_at(trip_id, csv_stop.stop_id)
+          .map(|stop_time| scheduled_candidate(arrival, trip, stop_time, csv_stop, *service_time))
+      })
+      .collect()
   }
 }
+
+/// Adds the `extra` candidates that aren't already among `candidates`
+fn add_candidates(candidates: &mut Vec<ArrivalData>, extra: Vec<ArrivalData>) {
+  for candidate in extra {
+    let known = candidates.iter().any(|known| {
+      known.trip_descriptor == candidate.trip_descriptor
+        && known.stop_time.stop_sequence == candidate.stop_time.stop_sequence
+    });
+    if !known {
+      candidates.push(candidate);
+    }
+  }
+}
+
+/// The arrival on a run of a trip without frequencies, at `stop_time`
+fn scheduled_candidate(
+  arrival: &Arrival,
+  trip: &CSVTrip,
+  stop_time: &StopTime,
+  csv_stop: &CSVStop,
+  service_time: ServiceTime,
+) -> ArrivalData {
+  ArrivalData {
+    arrival: arrival.clone(),
+    trip_descriptor: TripDescriptor {
+      trip_id: Some(trip.trip_id.to_string()),
+      route_id: Some(trip.route_id.to_string()),
+      direction_id: trip.direction_id.map(|direction_id| direction_id as u32),
+      start_time: None,
+      start_date: Some(service_time.start_date()),
+      schedule_relationship: None,
+    },
+    stop_time: stop_time.clone(),
+    scheduled_arrival: stop_time.arrival_time.1,
+    service_time,
+    csv_stop: csv_stop.clone(),
+    frequency: None,
+  }
+}
+
+/// The arrival on the run of a frequency-based trip that starts at `run`'s
+/// start time, in `run`'s headway window. `offset` is how long after the
+/// start of the run it reaches `stop_time`.
+fn frequency_candidate(
+  arrival: &Arrival,
+  trip: &CSVTrip,
+  stop_time: &StopTime,
+  csv_stop: &CSVStop,
+  service_time: ServiceTime,
+  run: (u64, &CSVFrequency),
+  offset: u64,
+) -> ArrivalData {
+  let (start_time, frequency) = run;
+  ArrivalData {
+    arrival: arrival.clone(),
+    trip_descriptor: TripDescriptor {
+      trip_id: Some(trip.trip_id.to_string()),
+      route_id: Some(trip.route_id.to_string()),
+      direction_id: trip.direction_id.map(|direction_id| direction_id as u32),
+      start_time: Some(day_time_serializer(start_time)),
+      start_date: Some(service_time.start_date()),
+      schedule_relationship: Some(ScheduleRelationship::Scheduled.into()),
+    },
+    stop_time: stop_time.clone(),
+    scheduled_arrival: start_time + offset,
+    service_time,
+    csv_stop: csv_stop.clone(),
+    frequency: Some(frequency.clone()),
+  }
+}
+
+/// Identifies one run of a trip: the same trip_id can run on several service
+/// days, and a frequency-based trip many times a day.
+pub fn trip_instance_id(trip: &TripDescriptor) -> String {
+  [&trip.trip_id, &trip.start_date, &trip.start_time]
+    .into_iter()
+    .flatten()
+    .join("-")
+}
+
+/// How much each signal counts against a candidate trip. Lower scores win.
+const TIME_WEIGHT: f64 = 1.0;
+const SEQUENCE_WEIGHT: f64 = 1.0;
+const HEADSIGN_WEIGHT: f64 = 0.5;
+const PATTERN_WEIGHT: f64 = 0.5;
+/// How much better another trip has to fit before a vehicle is moved off the
+/// one it's on
+const REASSIGN_MARGIN: f64 = 1.0;
+/// Scheduled times this far off count as a full TIME_WEIGHT
+const TIME_SCALE: f64 = 60.0 * 10.0;
+
+/// What we know about an arrival besides its own stop and time.
+struct MatchContext<'a> {
+  arrival: &'a Arrival,
+  /// The vehicle's other predictions this poll, as (GTFS stop_id, timestamp)
+  other_stops: Vec<(u64, i64)>,
+  /// Directions of the route's trips that share the arrival's headsign
+  headsign_directions: Option<&'a HashSet<u64>>,
+  pattern_direction: Option<u64>,
+}
+
+impl<'a> MatchContext<'a> {
+  fn new(
+    schedule: &'a Schedule,
+    arrival: &'a Arrival,
+    csv_route: &CSVRoute,
+    pattern_direction: Option<u64>,
+  ) -> MatchContext<'a> {
+    let other_stops = schedule
+      .vehicle_stops
+      .get(&arrival.vehicle_id)
+      .into_iter()
+      .flatten()
+      .filter(|(route_id, stop_id, _, _)| {
+        *route_id == arrival.route_id && *stop_id != arrival.stop_id
+      })
+      .map(|(_, _, csv_stop_id, timestamp)| (*csv_stop_id, *timestamp))
+      .collect();
+    let headsign_directions = arrival.headsign.as_deref().and_then(|headsign| {
+      schedule
+        .gtfs
+        .headsign_directions(csv_route.route_id, headsign)
+    });
+    MatchContext {
+      arrival,
+      other_stops,
+      headsign_directions,
+      pattern_direction,
+    }
+  }
+
+  fn score(&self, schedule: &Schedule, candidate: &ArrivalData) -> f64 {
+    let time_diff = (candidate.service_time.secs as f64 - candidate.scheduled_arrival as f64).abs();
+    let mut score = TIME_WEIGHT * (time_diff / TIME_SCALE).min(1.0);
+
+    // The vehicle's other stops should come before or after this one on the
+    // trip in the same order TransLoc expects to reach them
+    if !self.other_stops.is_empty() {
+      let trip_id = candidate.stop_time.trip_id;
+      let sequence = candidate.stop_time.stop_sequence;
+      let consistent = self
+        .other_stops
+        .iter()
+        .filter(|(stop_id, timestamp)| {
+          let later = *timestamp > self.arrival.timestamp;
+          schedule
+            .gtfs
+            .stop_times_at(trip_id, *stop_id)
+            .any(|stop_time| (stop_time.stop_sequence > sequence) == later)
+        })
+        .count();
+      score += SEQUENCE_WEIGHT * (1.0 - consistent as f64 / self.other_stops.len() as f64);
+    }
+
+    if let Some(trip) = schedule.gtfs.trips.get(&candidate.stop_time.trip_id) {
+      if let Some(headsign) = &self.arrival.headsign {
+        if !same_headsign(&trip.trip_headsign, headsign) {
+          let same_direction = trip
+            .direction_id
+            .zip(self.headsign_directions)
+            .map_or(false, |(direction, directions)| {
+              directions.contains(&direction)
+            });
+          score += HEADSIGN_WEIGHT * if same_direction { 0.5 } else { 1.0 };
+        }
+      }
+      if matches!(
+        (self.pattern_direction, trip.direction_id),
+        (Some(pattern), Some(direction)) if pattern != direction
+      ) {
+        score += PATTERN_WEIGHT;
+      }
+    }
+
+    score
+  }
+}
+
+fn same_headsign(a: &str, b: &str) -> bool {
+  a.trim().eq_ignore_ascii_case(b.trim())
+}
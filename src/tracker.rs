@@ -0,0 +1,110 @@
+use crate::schedule::{trip_instance_id, ArrivalData, MatchTrace, Schedule};
+use gtfs_rt::TripDescriptor;
+use std::collections::HashMap;
+
+/// Forget a vehicle that hasn't been matched for this long, in seconds
+const STALE_AFTER: i64 = 30 * 60;
+
+/// The trip instance a vehicle is running, and how far along it it is.
+#[derive(Debug, Clone)]
+pub struct Assignment {
+  pub trip: TripDescriptor,
+  /// stop_sequence of the next stop the vehicle is headed to
+  pub stop_sequence: u32,
+  pub updated_at: i64,
+}
+
+impl Assignment {
+  fn from_arrival(data: &ArrivalData, updated_at: i64) -> Assignment {
+    Assignment {
+      trip: data.trip_descriptor.clone(),
+      stop_sequence: data.stop_time.stop_sequence,
+      updated_at,
+    }
+  }
+}
+
+/// Remembers which trip each vehicle is on between polls, so a vehicle only
+/// changes trips when it finishes one or clearly isn't on it anymore.
+#[derive(Debug, Default)]
+pub struct VehicleTracker {
+  assignments: HashMap<u64, Assignment>,
+  /// The GTFS direction_id each TransLoc stop pattern last ran in
+  pattern_directions: HashMap<u64, u64>,
+}
+
+impl VehicleTracker {
+  pub fn get(&self, vehicle_id: u64) -> Option<&Assignment> {
+    self.assignments.get(&vehicle_id)
+  }
+
+  /// Matches every arrival in `schedule`, one vehicle at a time in the order
+  /// it'll reach its stops, and updates each vehicle's assignment.
+  pub fn match_arrivals(&mut self, schedule: &Schedule, now: i64) -> Vec<ArrivalData> {
+    self
+      .explain_arrivals(schedule, now)
+      .into_iter()
+      .filter_map(MatchTrace::into_match)
+      .collect()
+  }
+
+  /// `match_arrivals`, with every arrival's trace, matched or not.
+  pub fn explain_arrivals(&mut self, schedule: &Schedule, now: i64) -> Vec<MatchTrace> {
+    let mut by_vehicle: HashMap<u64, Vec<_>> = HashMap::new();
+    for arrival in &schedule.arrivals {
+      by_vehicle
+        .entry(arrival.vehicle_id)
+        .or_default()
+        .push(arrival);
+    }
+
+    let mut traces = vec![];
+    for (vehicle_id, mut arrivals) in by_vehicle {
+      arrivals.sort_by_key(|arrival| arrival.timestamp);
+      // Later arrivals can run past the end of the trip onto the next one, but
+      // the vehicle is on whichever trip its next stop belongs to
+      let mut working = self.assignments.get(&vehicle_id).cloned();
+      let pattern_id = schedule
+        .vehicles
+        .get(&vehicle_id)
+        .map(|vehicle| vehicle.stop_pattern_id);
+      let pattern_direction =
+        pattern_id.and_then(|pattern_id| self.pattern_directions.get(&pattern_id).copied());
+      let mut next = None;
+      for arrival in arrivals {
+        let trace = schedule.explain_arrival(arrival, working.as_ref(), pattern_direction);
+        if let Ok((index, _)) = trace.outcome {
+          let assignment = Assignment::from_arrival(&trace.candidates[index].1, now);
+          if next.is_none() {
+            next = Some(assignment.clone());
+          }
+          working = Some(assignment);
+        }
+        traces.push(trace);
+      }
+      if let Some(next) = next {
+        if let Some(previous) = self.assignments.get(&vehicle_id) {
+          if trip_instance_id(&previous.trip) != trip_instance_id(&next.trip) {
+            log::debug!(
+              "Vehicle {vehicle_id} moved from trip {} to {}",
+              trip_instance_id(&previous.trip),
+              trip_instance_id(&next.trip)
+            );
+          }
+        }
+        if let (Some(pattern_id), Some(direction_id)) = (pattern_id, next.trip.direction_id) {
+          self
+            .pattern_directions
+            .insert(pattern_id, u64::from(direction_id));
+        }
+        self.assignments.insert(vehicle_id, next);
+      }
+    }
+
+    self
+      .assignments
+      .retain(|_, assignment| now - assignment.updated_at < STALE_AFTER);
+    traces.sort_by_key(|trace| (trace.arrival.vehicle_id, trace.arrival.timestamp));
+    traces
+  }
+}
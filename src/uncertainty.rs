@@ -0,0 +1,129 @@
+use crate::schedule::Arrival;
+use std::collections::{HashMap, HashSet};
+
+/// Never claim to be more sure than this, in seconds
+const MIN_UNCERTAINTY: f64 = 15.0;
+/// Seconds of error per second of lookahead, until a route/stop has history
+const DEFAULT_ERROR_RATE: f64 = 0.15;
+/// Extra seconds of error per kilometre the vehicle still has to cover
+const ERROR_PER_KM: f64 = 10.0;
+/// How much each new observation moves a route/stop's error rate
+const SMOOTHING: f64 = 0.1;
+/// A prediction that vanishes this close to its time is taken as the vehicle
+/// having arrived, rather than e.g. going out of service
+const ARRIVED_WITHIN: i64 = 120;
+/// A prediction this much later than one that was about due is for the
+/// vehicle's next visit to the stop, e.g. its next lap of a loop
+const NEXT_VISIT_AFTER: i64 = 300;
+/// Predictions made closer than this tell us little about the error rate
+const MIN_LOOKAHEAD: i64 = 60;
+/// Predictions kept per vehicle and stop
+const MAX_PREDICTIONS: usize = 40;
+
+#[derive(Debug, Clone, Copy)]
+struct Prediction {
+  made_at: i64,
+  predicted: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorRate {
+  /// Mean absolute error per second of lookahead
+  pub rate: f64,
+  pub samples: u64,
+}
+
+/// How far off TransLoc's arrival predictions have been, per route and stop.
+/// The last prediction before an arrival drops out of the feed stands in for
+/// when the vehicle actually arrived.
+#[derive(Debug, Default)]
+pub struct PredictionHistory {
+  /// By (vehicle_id, route_id, stop_id)
+  pending: HashMap<(u64, u64, u64), Vec<Prediction>>,
+  /// By (route_id, stop_id)
+  error_rates: HashMap<(u64, u64), ErrorRate>,
+}
+
+impl PredictionHistory {
+  /// Records one poll's arrivals, made at `now`, and learns from any that
+  /// have since dropped out or moved on to the vehicle's next visit.
+  pub fn observe(&mut self, arrivals: &[Arrival], now: i64) {
+    let mut seen = HashSet::new();
+    for arrival in arrivals {
+      let key = (arrival.vehicle_id, arrival.route_id, arrival.stop_id);
+      seen.insert(key);
+      let predictions = self.pending.entry(key).or_default();
+      if let Some(last) = predictions.last() {
+        if last.predicted - now <= ARRIVED_WITHIN
+          && arrival.timestamp - last.predicted >= NEXT_VISIT_AFTER
+        {
+          // TransLoc moved straight on to the next visit, so the vehicle
+          // arrived without the prediction ever dropping out
+          let actual = last.predicted;
+          let visit = std::mem::take(predictions);
+          self.learn(arrival.route_id, arrival.stop_id, visit, actual);
+        }
+      }
+      let predictions = self.pending.entry(key).or_default();
+      if predictions.len() == MAX_PREDICTIONS {
+        predictions.remove(0);
+      }
+      predictions.push(Prediction {
+        made_at: now,
+        predicted: arrival.timestamp,
+      });
+    }
+
+    let gone: Vec<_> = self
+      .pending
+      .keys()
+      .filter(|key| !seen.contains(key))
+      .copied()
+      .collect();
+    for key in gone {
+      let predictions = self.pending.remove(&key).unwrap_or_default();
+      let actual = match predictions.last() {
+        Some(last) if last.predicted - now <= ARRIVED_WITHIN => last.predicted,
+        _ => continue,
+      };
+      let (_, route_id, stop_id) = key;
+      self.learn(route_id, stop_id, predictions, actual);
+    }
+  }
+
+  /// Scores one visit's `predictions` against when the vehicle `actual`ly
+  /// arrived.
+  fn learn(&mut self, route_id: u64, stop_id: u64, predictions: Vec<Prediction>, actual: i64) {
+    for prediction in predictions {
+      let lookahead = actual - prediction.made_at;
+      if lookahead < MIN_LOOKAHEAD {
+        continue;
+      }
+      let sample = (actual - prediction.predicted).abs() as f64 / lookahead as f64;
+      let error_rate = self
+        .error_rates
+        .entry((route_id, stop_id))
+        .or_insert(ErrorRate {
+          rate: sample,
+          samples: 0,
+        });
+      error_rate.rate += SMOOTHING * (sample - error_rate.rate);
+      error_rate.samples += 1;
+    }
+  }
+
+  pub fn error_rate(&self, route_id: u64, stop_id: u64) -> Option<ErrorRate> {
+    self.error_rates.get(&(route_id, stop_id)).copied()
+  }
+
+  /// Expected error of `arrival`'s prediction in seconds, for
+  /// `StopTimeEvent.uncertainty`
+  pub fn uncertainty(&self, arrival: &Arrival, now: i64) -> i32 {
+    let rate = self
+      .error_rate(arrival.route_id, arrival.stop_id)
+      .map_or(DEFAULT_ERROR_RATE, |error_rate| error_rate.rate);
+    let lookahead = (arrival.timestamp - now).max(0) as f64;
+    let distance = arrival.distance.max(0.0) / 1000.0;
+    (MIN_UNCERTAINTY + rate * lookahead + ERROR_PER_KM * distance).round() as i32
+  }
+}
@@ -0,0 +1,131 @@
+#![allow(dead_code)]
+
+use rit_gtfsrt::config::Config;
+use rit_gtfsrt::crosswalk::CrosswalkFile;
+use rit_gtfsrt::gtfs::Gtfs;
+use rit_gtfsrt::poller::Snapshots;
+use rit_gtfsrt::protobuf_route::{legacy_protobuf_route, protobuf_route, FeedKind};
+use rit_gtfsrt::registry::{Registry, TranslocAgencies};
+use rit_gtfsrt::schedule::{
+  MatchSettings, RouteOutput, Schedule, ScheduleCache, StopOutput, VehicleStatuses,
+};
+use rit_gtfsrt::State;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::sync::Arc;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+pub const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+/// 2023-09-12 08:00:00 America/New_York, when the TransLoc fixtures were taken
+pub const T0: i64 = 1694520000;
+
+/// Zips up the fixture GTFS the same way the agency would publish it.
+pub fn fixture_zip() -> Vec<u8> {
+  fixture_zip_without(&[])
+}
+
+/// The fixture GTFS zip, leaving out the files named in `skip`
+pub fn fixture_zip_without(skip: &[&str]) -> Vec<u8> {
+  fixture_zip_edited(|name, contents| (!skip.contains(&name)).then_some(contents))
+}
+
+/// The fixture GTFS zip, with each file's contents as `edit` returns them.
+/// Files it returns `None` for are left out.
+pub fn fixture_zip_edited(edit: impl Fn(&str, String) -> Option<String>) -> Vec<u8> {
+  let mut zip = ZipWriter::new(Cursor::new(vec![]));
+  for entry in fs::read_dir(Path::new(FIXTURES).join("gtfs")).unwrap() {
+    let path = entry.unwrap().path();
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let Some(contents) = edit(name, fs::read_to_string(&path).unwrap()) else {
+      continue;
+    };
+    zip.start_file(name, FileOptions::default()).unwrap();
+    zip.write_all(contents.as_bytes()).unwrap();
+  }
+  zip.finish().unwrap().into_inner()
+}
+
+pub fn fixture_gtfs() -> Gtfs {
+  Gtfs::from_zip(fixture_zip()).unwrap()
+}
+
+/// The fixture GTFS with `file`'s contents as `edit` returns them
+pub fn fixture_gtfs_editing(file: &str, edit: impl Fn(String) -> String) -> Gtfs {
+  let zip = fixture_zip_edited(|name, contents| {
+    Some(if name == file {
+      edit(contents)
+    } else {
+      contents
+    })
+  });
+  Gtfs::from_zip(zip).unwrap()
+}
+
+pub fn transloc<T: DeserializeOwned>(name: &str) -> T {
+  let path = Path::new(FIXTURES).join("transloc").join(name);
+  serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// The fixture GTFS and TransLoc stops/routes, with `vehicle_statuses`
+pub fn schedule_with(vehicle_statuses: VehicleStatuses, crosswalk: CrosswalkFile) -> Schedule {
+  schedule_with_settings(vehicle_statuses, |settings| {
+    settings.crosswalk = Arc::new(crosswalk)
+  })
+}
+
+/// `schedule_with`, after `adjust` has had a go at the default settings
+pub fn schedule_with_settings(
+  vehicle_statuses: VehicleStatuses,
+  adjust: impl FnOnce(&mut MatchSettings),
+) -> Schedule {
+  let gtfs = fixture_gtfs();
+  let mut settings = match_settings(&gtfs);
+  adjust(&mut settings);
+  Schedule::new(
+    Arc::new(gtfs),
+    transloc::<StopOutput>("stops.json"),
+    transloc::<RouteOutput>("routes.json"),
+    vehicle_statuses,
+    settings,
+    &mut ScheduleCache::default(),
+  )
+}
+
+/// The settings the fixture agency would have by default
+pub fn match_settings(gtfs: &Gtfs) -> MatchSettings {
+  MatchSettings {
+    timezone: gtfs.timezone.unwrap(),
+    service_day_cutoff: 4,
+    match_ambiguity: 0.1,
+    stop_match_distance: 50.0,
+    crosswalk: Default::default(),
+  }
+}
+
+/// The feed routes for an agency "rit" serving `snapshots`
+pub fn app(snapshots: Snapshots) -> tide::Server<State> {
+  let config: Config = toml::from_str(
+    r#"
+    [[agencies]]
+    id = 643
+    code = "rit"
+    "#,
+  )
+  .unwrap();
+  let registry = Registry::build(&config, &TranslocAgencies::default()).unwrap();
+  let mut app = tide::with_state(State {
+    config: Arc::new(config),
+    registry: Arc::new(registry),
+    snapshots,
+  });
+  app
+    .at("/rt/:agency")
+    .get(|req| protobuf_route(req, FeedKind::Combined));
+  app
+    .at("/rt/:agency_id/:agency_code")
+    .get(legacy_protobuf_route);
+  app
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use chrono_tz::America::{Chicago, Denver, Los_Angeles, New_York};
+use clap::Parser;
+use common::{fixture_gtfs, fixture_gtfs_editing};
+use rit_gtfsrt::config::{Args, Config};
+use rit_gtfsrt::gtfs::GtfsSource;
+use std::fs;
+use std::path::PathBuf;
+
+fn config(toml: &str) -> Config {
+  toml::from_str(toml).unwrap()
+}
+
+/// Writes `toml` to a config file named after `name`, for `--config`
+fn config_file(name: &str, toml: &str) -> PathBuf {
+  let path = std::env::temp_dir().join(format!("rit_gtfsrt-{name}-{}.toml", std::process::id()));
+  fs::write(&path, toml).unwrap();
+  path
+}
+
+/// Loads the config the way `main` does, from `args` on the command line
+fn load(args: &[&str]) -> Config {
+  let args = Args::try_parse_from(["rit_gtfsrt"].iter().chain(args)).unwrap();
+  Config::load(args).unwrap()
+}
+
+#[test]
+fn cli_and_env_override_the_file() {
+  let path = config_file(
+    "overrides",
+    r#"
+    listen = "127.0.0.1:1234"
+    transloc_url = "https://file.example/3"
+    poll_interval = 30
+    "#,
+  );
+  let path = path.to_str().unwrap();
+  let config = load(&["--config", path]);
+  assert_eq!(config.listen, "127.0.0.1:1234");
+  assert_eq!(config.transloc_url, "https://file.example/3");
+  assert_eq!(config.poll_interval, 30);
+
+  std::env::set_var("RIT_GTFSRT_TRANSLOC_URL", "https://env.example/3");
+  let config = load(&["--config", path, "--listen", "0.0.0.0:80"]);
+  std::env::remove_var("RIT_GTFSRT_TRANSLOC_URL");
+  assert_eq!(config.listen, "0.0.0.0:80");
+  assert_eq!(config.transloc_url, "https://env.example/3");
+  // Whatever isn't overridden still comes from the file
+  assert_eq!(config.poll_interval, 30);
+  fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn files_without_agencies_serve_rit() {
+  let path = config_file("no-agencies", r#"listen = "127.0.0.1:1234""#);
+  let config = load(&["--config", path.to_str().unwrap()]);
+  let codes: Vec<_> = config
+    .agencies
+    .iter()
+    .map(|agency| (agency.id, agency.code.as_str()))
+    .collect();
+  assert_eq!(codes, vec![(Some(643), "rit")]);
+  fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn gtfs_source_joins_the_base_url_and_code() {
+  for base in ["https://gtfs.example/feeds", "https://gtfs.example/feeds/"] {
+    let config = config(&format!(
+      r#"
+      gtfs_base_url = "{base}"
+      [[agencies]]
+      code = "rit"
+      "#
+    ));
+    assert_eq!(
+      config.gtfs_source(&config.agencies[0]),
+      GtfsSource::Url("https://gtfs.example/feeds/rit.zip".to_owned())
+    );
+  }
+}
+
+#[test]
+fn agency_timezone_comes_from_agency_txt() {
+  let config = Config::default();
+  let agency = &config.agencies[0];
+  let gtfs = fixture_gtfs_editing("agency.txt", |csv| {
+    csv.replace("America/New_York", "America/Chicago")
+  });
+  assert_eq!(gtfs.timezone, Some(Chicago));
+  assert_eq!(config.timezone(agency, &gtfs), Chicago);
+}
+
+#[test]
+fn configured_timezones_win_over_agency_txt() {
+  let gtfs = fixture_gtfs_editing("agency.txt", |csv| {
+    csv.replace("America/New_York", "America/Chicago")
+  });
+  let global = config(
+    r#"
+    timezone = "America/Denver"
+    [[agencies]]
+    code = "rit"
+    "#,
+  );
+  assert_eq!(global.timezone(&global.agencies[0], &gtfs), Denver);
+
+  // The agency's own setting wins over the global one
+  let agency = config(
+    r#"
+    timezone = "America/Denver"
+    [[agencies]]
+    code = "rit"
+    timezone = "America/Los_Angeles"
+    "#,
+  );
+  assert_eq!(agency.timezone(&agency.agencies[0], &gtfs), Los_Angeles);
+}
+
+#[test]
+fn timezone_defaults_without_agency_txt_or_config() {
+  // Only the header row, so agency.txt names no timezone
+  let gtfs = fixture_gtfs_editing("agency.txt", |csv| csv.lines().next().unwrap().to_owned());
+  assert_eq!(gtfs.timezone, None);
+  let config = Config::default();
+  assert_eq!(config.timezone(&config.agencies[0], &gtfs), New_York);
+  // And agency.txt is what the fixture runs on otherwise
+  assert_eq!(fixture_gtfs().timezone, Some(New_York));
+}
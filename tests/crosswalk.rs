@@ -0,0 +1,302 @@
+mod common;
+
+use common::{fixture_gtfs, fixture_gtfs_editing, match_settings, transloc};
+use rit_gtfsrt::crosswalk::{
+  distance, normalize_name, CrosswalkFile, CrosswalkReport, RouteCrosswalk, RouteRule,
+  StopCrosswalk, StopRule, TranslocRoute, TranslocStop,
+};
+use rit_gtfsrt::crosswalk_cli::proposal;
+use rit_gtfsrt::gtfs::Gtfs;
+use rit_gtfsrt::schedule::{Schedule, ScheduleCache};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+fn route(id: u64, short_name: &'static str, long_name: &'static str) -> TranslocRoute<'static> {
+  TranslocRoute {
+    id,
+    short_name,
+    long_name,
+  }
+}
+
+/// `csv` with every row's `column` set to `value`
+fn set_column(csv: String, column: usize, value: &str) -> String {
+  let mut lines = csv.lines();
+  let mut edited = vec![lines.next().unwrap().to_owned()];
+  for line in lines {
+    let mut fields: Vec<&str> = line.split(',').collect();
+    fields[column] = value;
+    edited.push(fields.join(","));
+  }
+  edited.join("\n") + "\n"
+}
+
+fn rule_for(crosswalk: &RouteCrosswalk, id: u64) -> Option<(u64, RouteRule)> {
+  crosswalk
+    .get(id)
+    .map(|route_match| (route_match.route_id, route_match.rule))
+}
+
+#[test]
+fn names_are_normalized() {
+  assert_eq!(normalize_name("  Park-Point "), "park point");
+  assert_eq!(normalize_name("PARK   point"), "park point");
+}
+
+#[test]
+fn routes_match_by_id_short_name_then_long_name() {
+  let gtfs = fixture_gtfs();
+  let crosswalk = RouteCrosswalk::new(
+    [
+      route(101, "", ""),
+      route(4000102, "2", "Something else"),
+      route(4000103, "", "province "),
+      route(4000104, "", "Gone"),
+    ],
+    &gtfs,
+    &CrosswalkFile::default(),
+  );
+  assert_eq!(rule_for(&crosswalk, 101), Some((101, RouteRule::Id)));
+  assert_eq!(
+    rule_for(&crosswalk, 4000102),
+    Some((102, RouteRule::ShortName))
+  );
+  assert_eq!(
+    rule_for(&crosswalk, 4000103),
+    Some((103, RouteRule::LongName))
+  );
+  assert_eq!(rule_for(&crosswalk, 4000104), None);
+  let report = CrosswalkReport::new(&crosswalk, &StopCrosswalk::default());
+  assert_eq!(report.unmapped_routes, vec![4000104]);
+}
+
+#[test]
+fn overrides_win() {
+  let gtfs = fixture_gtfs();
+  let overrides = CrosswalkFile {
+    routes: BTreeMap::from([("4000101".to_owned(), 103)]),
+    ..Default::default()
+  };
+  let crosswalk = RouteCrosswalk::new([route(4000101, "1", "Campus Loop")], &gtfs, &overrides);
+  assert_eq!(
+    rule_for(&crosswalk, 4000101),
+    Some((103, RouteRule::Override))
+  );
+}
+
+#[test]
+fn ambiguous_routes_are_reported() {
+  // Every route_short_name is X
+  let gtfs = fixture_gtfs_editing("routes.txt", |csv| set_column(csv, 1, "X"));
+  let crosswalk = RouteCrosswalk::new([route(4000101, "X", "")], &gtfs, &CrosswalkFile::default());
+  assert_eq!(rule_for(&crosswalk, 4000101), None);
+  let report = CrosswalkReport::new(&crosswalk, &StopCrosswalk::default());
+  assert_eq!(
+    report.ambiguous_routes,
+    BTreeMap::from([(4000101, vec![101, 102, 103])])
+  );
+  // As /debug/:agency/crosswalk serves it
+  let json = serde_json::to_value(&report).unwrap();
+  assert_eq!(
+    json["ambiguous_routes"],
+    serde_json::json!({ "4000101": [101, 102, 103] })
+  );
+}
+
+/// Gleason Circle in the fixture GTFS (stop 1)
+const GLEASON: [f64; 2] = [43.08447, -77.6749];
+
+fn stop(
+  id: u64,
+  code: &'static str,
+  name: &'static str,
+  position: [f64; 2],
+) -> TranslocStop<'static> {
+  TranslocStop {
+    id,
+    code,
+    name,
+    position,
+  }
+}
+
+fn stop_rule_for(crosswalk: &StopCrosswalk, id: u64) -> Option<(u64, StopRule)> {
+  crosswalk
+    .get(id)
+    .map(|stop_match| (stop_match.stop_id, stop_match.rule))
+}
+
+#[test]
+fn stops_match_by_code_name_then_position() {
+  let gtfs = fixture_gtfs();
+  // About 30m north of Gleason Circle
+  let nearby = [GLEASON[0] + 0.00027, GLEASON[1]];
+  let far_away = [GLEASON[0] + 0.01, GLEASON[1]];
+  let crosswalk = StopCrosswalk::new(
+    [
+      stop(4100001, "1", "Renamed", far_away),
+      stop(4100002, "", "global  village", far_away),
+      stop(4100003, "", "Somewhere", nearby),
+      stop(4100004, "", "Somewhere", far_away),
+    ],
+    &gtfs,
+    &CrosswalkFile::default(),
+    50.0,
+  );
+  assert_eq!(
+    stop_rule_for(&crosswalk, 4100001),
+    Some((1, StopRule::Code))
+  );
+  assert_eq!(
+    stop_rule_for(&crosswalk, 4100002),
+    Some((2, StopRule::Name))
+  );
+  assert_eq!(
+    stop_rule_for(&crosswalk, 4100003),
+    Some((1, StopRule::Nearest))
+  );
+  let nearest = crosswalk.get(4100003).unwrap();
+  assert!((nearest.distance - 30.0).abs() < 1.0, "{nearest:?}");
+  assert_eq!(stop_rule_for(&crosswalk, 4100004), None);
+  let report = CrosswalkReport::new(&RouteCrosswalk::default(), &crosswalk);
+  assert_eq!(report.unmapped_stops, vec![4100004]);
+}
+
+#[test]
+fn reused_stop_codes_fall_back_to_position() {
+  // Every stop_code is X
+  let gtfs = fixture_gtfs_editing("stops.txt", |csv| set_column(csv, 1, "X"));
+  let crosswalk = StopCrosswalk::new(
+    [
+      stop(4100001, "X", "", GLEASON),
+      stop(4100002, "X", "", [0.0, 0.0]),
+    ],
+    &gtfs,
+    &CrosswalkFile::default(),
+    50.0,
+  );
+  assert_eq!(
+    stop_rule_for(&crosswalk, 4100001),
+    Some((1, StopRule::Nearest))
+  );
+  let report = CrosswalkReport::new(&RouteCrosswalk::default(), &crosswalk);
+  assert_eq!(
+    report.ambiguous_stops.keys().collect::<Vec<_>>(),
+    vec![&4100002]
+  );
+}
+
+#[test]
+fn stop_overrides_win() {
+  let gtfs = fixture_gtfs();
+  let overrides = CrosswalkFile {
+    stops: BTreeMap::from([("4100001".to_owned(), 12)]),
+    ..Default::default()
+  };
+  let crosswalk = StopCrosswalk::new(
+    [stop(4100001, "1", "Gleason Circle", GLEASON)],
+    &gtfs,
+    &overrides,
+    50.0,
+  );
+  assert_eq!(
+    stop_rule_for(&crosswalk, 4100001),
+    Some((12, StopRule::Override))
+  );
+}
+
+#[test]
+fn distances_are_in_metres() {
+  // A degree of latitude is about 111km
+  let metres = distance([43.0, -77.0], [44.0, -77.0]);
+  assert!((metres - 111_195.0).abs() < 100.0, "{metres}");
+}
+
+#[test]
+fn proposal_loads_as_a_crosswalk() {
+  let gtfs = fixture_gtfs();
+  let routes = [
+    route(4000101, "Campus Loop", "Campus Loop"),
+    route(4000199, "", "Gone"),
+  ];
+  let stops = [
+    stop(4100001, "1", "Gleason Circle", GLEASON),
+    stop(4100099, "1", "Gleason Circle", GLEASON),
+  ];
+  let route_crosswalk = RouteCrosswalk::new(routes, &gtfs, &CrosswalkFile::default());
+  let stop_crosswalk = StopCrosswalk::new(stops, &gtfs, &CrosswalkFile::default(), 50.0);
+  let report = CrosswalkReport::new(&route_crosswalk, &stop_crosswalk);
+  let text = proposal(&report, &routes, &stops, &gtfs);
+
+  let loaded: CrosswalkFile = toml::from_str(&text).unwrap();
+  assert_eq!(loaded.routes, BTreeMap::from([("4000101".to_owned(), 101)]));
+  assert_eq!(
+    loaded.stops,
+    BTreeMap::from([("4100001".to_owned(), 1), ("4100099".to_owned(), 1)])
+  );
+  assert!(text.contains("# GTFS stop 1 is matched by TransLoc stops [4100001, 4100099]"));
+  assert!(text.contains("# TransLoc route 4000199:"));
+  assert!(text.contains("# GTFS route 102: "));
+}
+
+#[test]
+fn crosswalks_are_kept_until_their_inputs_change() {
+  let gtfs = Arc::new(fixture_gtfs());
+  let settings = match_settings(&gtfs);
+  let mut cache = ScheduleCache::default();
+  let mut schedule = |gtfs: &Arc<Gtfs>, stops: Value| {
+    Schedule::new(
+      gtfs.clone(),
+      serde_json::from_value(stops).unwrap(),
+      transloc("routes.json"),
+      transloc("vehicle_statuses.json"),
+      settings.clone(),
+      &mut cache,
+    )
+  };
+  let stops: Value = transloc("stops.json");
+  let first = schedule(&gtfs, stops.clone());
+  let again = schedule(&gtfs, stops.clone());
+  assert!(Arc::ptr_eq(&first.route_crosswalk, &again.route_crosswalk));
+  assert!(Arc::ptr_eq(&first.stop_crosswalk, &again.stop_crosswalk));
+
+  // A renamed TransLoc stop only rebuilds the stops
+  let mut renamed = stops.clone();
+  renamed["stops"][0]["name"] = json!("Gleason Circle (temporary)");
+  let renamed = schedule(&gtfs, renamed);
+  assert!(Arc::ptr_eq(
+    &first.route_crosswalk,
+    &renamed.route_crosswalk
+  ));
+  assert!(!Arc::ptr_eq(&first.stop_crosswalk, &renamed.stop_crosswalk));
+
+  // A new GTFS rebuilds both
+  let reloaded = schedule(&Arc::new(fixture_gtfs()), stops);
+  assert!(!Arc::ptr_eq(
+    &first.route_crosswalk,
+    &reloaded.route_crosswalk
+  ));
+  assert!(!Arc::ptr_eq(
+    &renamed.stop_crosswalk,
+    &reloaded.stop_crosswalk
+  ));
+}
+
+#[test]
+fn stations_are_never_the_nearest_stop() {
+  // Stop 1 as a station, right where a TransLoc stop that matched nothing is
+  let gtfs = fixture_gtfs_editing("stops.txt", |csv| {
+    csv.replace(
+      "1,1,Gleason Circle,,43.08447,-77.6749,,0",
+      "1,1,Gleason Circle,,43.08447,-77.6749,,1",
+    )
+  });
+  let crosswalk = StopCrosswalk::new(
+    [stop(4100099, "", "", GLEASON)],
+    &gtfs,
+    &CrosswalkFile::default(),
+    50.0,
+  );
+  assert_eq!(stop_rule_for(&crosswalk, 4100099), None);
+}
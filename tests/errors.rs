@@ -0,0 +1,101 @@
+mod common;
+
+use common::app;
+use rit_gtfsrt::poller::{FeedStatus, Snapshots};
+use rit_gtfsrt::protobuf_route::GenFeedError;
+use serde_json::Value;
+use std::net::TcpListener;
+use std::time::Duration;
+use tide::http::{Request, Response, Url};
+use tide::StatusCode;
+use zip::result::ZipError;
+
+/// The status and JSON body `path` answers with
+async fn get(snapshots: &Snapshots, path: &str) -> (StatusCode, Value) {
+  let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+  let mut response: Response = app(snapshots.clone())
+    .respond(Request::get(url))
+    .await
+    .unwrap();
+  (response.status(), response.body_json().await.unwrap())
+}
+
+/// Records `err` as why the last poll of rit failed
+fn failed_with(err: GenFeedError) -> Snapshots {
+  let snapshots = Snapshots::default();
+  snapshots.write().unwrap().insert(
+    "rit".to_owned(),
+    FeedStatus {
+      snapshot: None,
+      failure: Some((&err).into()),
+    },
+  );
+  snapshots
+}
+
+/// An HTTP error from a server that accepts the connection but never answers
+async fn timeout_error() -> reqwest::Error {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/", listener.local_addr().unwrap());
+  reqwest::Client::builder()
+    .timeout(Duration::from_millis(100))
+    .build()
+    .unwrap()
+    .get(url)
+    .send()
+    .await
+    .unwrap_err()
+}
+
+#[async_std::test]
+async fn upstream_timeouts_are_504s() {
+  let err = GenFeedError::Http(timeout_error().await, "https://transloc/stops".to_owned());
+  assert_eq!(err.status(), StatusCode::GatewayTimeout);
+
+  let (status, body) = get(&failed_with(err), "/rt/rit").await;
+  assert_eq!(status, StatusCode::GatewayTimeout);
+  assert_eq!(body["status"], 504);
+  assert_eq!(body["error"], "upstream");
+  assert_eq!(body["source"], "https://transloc/stops");
+}
+
+#[async_std::test]
+async fn other_upstream_failures_are_502s_naming_their_source() {
+  let err = GenFeedError::Zip(ZipError::FileNotFound, "https://gtfs/rit.zip".to_owned());
+  assert_eq!(err.status(), StatusCode::BadGateway);
+
+  let (status, body) = get(&failed_with(err), "/rt/rit").await;
+  assert_eq!(status, StatusCode::BadGateway);
+  assert_eq!(body["status"], 502);
+  assert_eq!(body["error"], "upstream");
+  assert_eq!(body["source"], "https://gtfs/rit.zip");
+}
+
+#[async_std::test]
+async fn feeds_are_503s_until_the_first_poll() {
+  let (status, body) = get(&Snapshots::default(), "/rt/rit").await;
+  assert_eq!(status, StatusCode::ServiceUnavailable);
+  assert_eq!(body["error"], "not_ready");
+  assert!(body.get("source").is_none());
+}
+
+#[async_std::test]
+async fn bad_input_is_a_400() {
+  let snapshots = Snapshots::default();
+  for path in ["/rt/rit?times=sometimes", "/rt/six-four-three/rit"] {
+    let (status, body) = get(&snapshots, path).await;
+    assert_eq!(status, StatusCode::BadRequest, "{path}");
+    assert_eq!(body["error"], "bad_request", "{path}");
+  }
+}
+
+#[async_std::test]
+async fn unknown_agencies_are_404s() {
+  let snapshots = Snapshots::default();
+  // Unknown codes, and known codes with the wrong id
+  for path in ["/rt/elsewhere", "/rt/99/rit"] {
+    let (status, body) = get(&snapshots, path).await;
+    assert_eq!(status, StatusCode::NotFound, "{path}");
+    assert_eq!(body["error"], "unknown_agency", "{path}");
+  }
+}
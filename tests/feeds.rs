@@ -0,0 +1,130 @@
+mod common;
+
+use common::app;
+use gtfs_rt::{Alert, FeedEntity, FeedMessage, TripUpdate, VehiclePosition};
+use prost::Message;
+use rit_gtfsrt::error::ApiError;
+use rit_gtfsrt::poller::{Failure, FeedPart, FeedStatus, Snapshot, Snapshots};
+use rit_gtfsrt::protobuf_route::FeedKind;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tide::http::{Request, Response, Url};
+use tide::StatusCode;
+
+fn part(entity: FeedEntity, timestamp: u64) -> FeedPart {
+  FeedPart {
+    entity: vec![entity],
+    timestamp,
+  }
+}
+
+/// One entity of each type, each part fetched at a different time
+fn snapshot() -> Snapshot {
+  Snapshot {
+    alerts: Ok(part(
+      FeedEntity {
+        id: "alert".to_owned(),
+        alert: Some(Alert::default()),
+        ..Default::default()
+      },
+      100,
+    )),
+    trip_updates: part(
+      FeedEntity {
+        id: "trip_update".to_owned(),
+        trip_update: Some(TripUpdate::default()),
+        ..Default::default()
+      },
+      200,
+    ),
+    vehicle_positions: part(
+      FeedEntity {
+        id: "vehicle".to_owned(),
+        vehicle: Some(VehiclePosition::default()),
+        ..Default::default()
+      },
+      300,
+    ),
+    crosswalk: Default::default(),
+    matches: vec![],
+    updated_at: SystemTime::now(),
+  }
+}
+
+#[test]
+fn each_feed_carries_only_its_own_entities() {
+  let snapshot = snapshot();
+  for kind in FeedKind::ALL {
+    let feed = kind.message(&snapshot).unwrap();
+    let ids: Vec<_> = feed
+      .entity
+      .iter()
+      .map(|entity| entity.id.as_str())
+      .collect();
+    let (expected_ids, timestamp) = match kind {
+      // Stamped with the oldest part, so it never claims to be fresher than it is
+      FeedKind::Combined => (vec!["alert", "trip_update", "vehicle"], 100),
+      FeedKind::TripUpdates => (vec!["trip_update"], 200),
+      FeedKind::VehiclePositions => (vec!["vehicle"], 300),
+      FeedKind::Alerts => (vec!["alert"], 100),
+    };
+    assert_eq!(ids, expected_ids, "{}", kind.name());
+    assert_eq!(feed.header.timestamp, Some(timestamp), "{}", kind.name());
+  }
+}
+
+#[test]
+fn alerts_that_never_loaded_are_an_error() {
+  let mut snapshot = snapshot();
+  snapshot.alerts = Err(Failure {
+    status: StatusCode::BadGateway,
+    source: "https://transloc/announcements".to_owned(),
+    message: "Announcements are down".to_owned(),
+    failed_at: SystemTime::now(),
+  });
+  match FeedKind::Alerts.message(&snapshot) {
+    Err(ApiError::Upstream(failure)) => {
+      assert_eq!(failure.source, "https://transloc/announcements")
+    }
+    other => panic!("Expected an upstream error, got {other:?}"),
+  }
+
+  // The rest of the combined feed doesn't wait on them
+  let feed = FeedKind::Combined.message(&snapshot).unwrap();
+  let ids: Vec<_> = feed
+    .entity
+    .iter()
+    .map(|entity| entity.id.as_str())
+    .collect();
+  assert_eq!(ids, vec!["trip_update", "vehicle"]);
+  assert_eq!(feed.header.timestamp, Some(200));
+}
+
+/// The ids of the entities `path` serves for `snapshot`
+async fn served_ids(snapshot: Snapshot, path: &str) -> Vec<String> {
+  let snapshots = Snapshots::default();
+  snapshots.write().unwrap().insert(
+    "rit".to_owned(),
+    FeedStatus {
+      snapshot: Some(Arc::new(snapshot)),
+      failure: None,
+    },
+  );
+  let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+  let mut response: Response = app(snapshots).respond(Request::get(url)).await.unwrap();
+  assert_eq!(response.status(), StatusCode::Ok);
+  let feed = FeedMessage::decode(response.body_bytes().await.unwrap().as_slice()).unwrap();
+  feed.entity.into_iter().map(|entity| entity.id).collect()
+}
+
+#[async_std::test]
+async fn positions_only_leaves_trip_updates_out() {
+  assert_eq!(
+    served_ids(snapshot(), "/rt/rit").await,
+    vec!["alert", "trip_update", "vehicle"]
+  );
+  assert_eq!(
+    served_ids(snapshot(), "/rt/rit?positions_only=true").await,
+    vec!["alert", "vehicle"]
+  );
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use chrono::NaiveDate;
+use common::{fixture_gtfs, fixture_gtfs_editing, fixture_zip, fixture_zip_without};
+use rit_gtfsrt::gtfs::{load_gtfs, Gtfs, GtfsSource};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+fn date(ymd: &str) -> NaiveDate {
+  NaiveDate::parse_from_str(ymd, "%Y%m%d").unwrap()
+}
+
+fn hms(h: u64, m: u64, s: u64) -> u64 {
+  h * 3600 + m * 60 + s
+}
+
+/// Weekday service in the fixture calendar
+const WEEKDAYS: u64 = 1;
+/// Weekend service, which also runs on holidays
+const WEEKENDS: u64 = 2;
+
+#[test]
+fn frequencies_are_optional() {
+  let gtfs = Gtfs::from_zip(fixture_zip_without(&["frequencies.txt"])).unwrap();
+  assert!(gtfs.frequencies.is_empty());
+  assert!(!gtfs.trips.is_empty());
+}
+
+#[test]
+fn optional_columns_can_be_left_out() {
+  let gtfs = fixture_gtfs_editing("trips.txt", |csv| {
+    csv
+      .lines()
+      .map(|line| line.splitn(10, ',').take(9).collect::<Vec<_>>().join(","))
+      .collect::<Vec<_>>()
+      .join("\n")
+  });
+  assert_eq!(gtfs.trips.len(), 383);
+  assert_eq!(gtfs.trips[&1001].block_id, None);
+}
+
+#[test]
+fn blank_location_type_is_a_stop() {
+  let gtfs = fixture_gtfs_editing("stops.txt", |csv| csv.replace(",,0\n", ",,\n"));
+  assert_eq!(gtfs.stops.len(), 12);
+  assert!(gtfs.stops.values().all(|stop| stop.location_type == 0));
+}
+
+#[test]
+fn malformed_times_skip_their_row() {
+  let gtfs = fixture_gtfs_editing("stop_times.txt", |csv| {
+    csv.replace("1008,08:00:00,08:00:00,", "1008,8:00,8:00,")
+  });
+  let sequences: Vec<_> = gtfs
+    .stop_times(1008)
+    .iter()
+    .map(|stop_time| stop_time.stop_sequence)
+    .collect();
+  assert_eq!(sequences, vec![2, 3, 4, 5]);
+}
+
+#[test]
+fn stop_times_are_looked_up_by_arrival() {
+  let gtfs = fixture_gtfs();
+  let (from, to) = (hms(7, 50, 0), hms(8, 10, 0));
+  let stop_times: Vec<_> = gtfs.stop_times_between(102, 1, from, to).collect();
+  let trip_ids: Vec<_> = stop_times
+    .iter()
+    .map(|stop_time| stop_time.trip_id)
+    .collect();
+  // Both ends of the window count
+  for trip_id in [1007, 1008, 1009, 1118] {
+    assert!(trip_ids.contains(&trip_id), "{trip_id}");
+  }
+  let arrivals: Vec<_> = stop_times
+    .iter()
+    .map(|stop_time| stop_time.arrival_time.1)
+    .collect();
+  assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+  assert!(arrivals.iter().all(|arrival| (from..=to).contains(arrival)));
+  assert!(stop_times.iter().all(|stop_time| stop_time.stop_id == 1));
+
+  // The Campus Loop only runs on frequencies, so it has no fixed times
+  assert_eq!(gtfs.stop_times_between(101, 1, from, to).count(), 0);
+  let frequency_trips: Vec<_> = gtfs
+    .frequency_trips_for_route(101)
+    .map(|trip| trip.trip_id)
+    .collect();
+  assert_eq!(frequency_trips, vec![1001]);
+}
+
+#[test]
+fn weekday_trips_skip_holidays() {
+  let gtfs = fixture_gtfs();
+  let campus_loop = gtfs.trips[&1001].service_id;
+  assert_eq!(campus_loop, WEEKDAYS);
+  // Tuesday 2023-09-12, an ordinary weekday
+  assert!(gtfs.is_service_active(campus_loop, date("20230912")));
+  // Labor Day and fall break are removed by calendar_dates.txt
+  assert!(!gtfs.is_service_active(campus_loop, date("20230904")));
+  assert!(!gtfs.is_service_active(campus_loop, date("20231009")));
+  assert!(!gtfs.is_service_active(campus_loop, date("20231010")));
+}
+
+#[test]
+fn added_service_exceptions_are_honoured() {
+  let gtfs = fixture_gtfs();
+  // A Monday, so weekend service only runs because it's added for the holiday
+  assert!(!gtfs.is_service_active(WEEKENDS, date("20230911")));
+  assert!(gtfs.is_service_active(WEEKENDS, date("20230904")));
+  assert!(gtfs.is_service_active(WEEKENDS, date("20231010")));
+}
+
+#[test]
+fn weekends_follow_calendar_txt() {
+  let gtfs = fixture_gtfs();
+  // Saturday and Sunday
+  for day in ["20230909", "20230910"] {
+    assert!(!gtfs.is_service_active(WEEKDAYS, date(day)));
+    assert!(gtfs.is_service_active(WEEKENDS, date(day)));
+  }
+  // Past the calendar's end_date nothing runs
+  assert!(!gtfs.is_service_active(WEEKENDS, date("20231216")));
+  assert!(!gtfs.is_service_active(WEEKDAYS, date("20231218")));
+}
+
+/// Writes `bytes` to `path`, again if need be, until its modification time
+/// changes, so a coarse filesystem clock can't hide the rewrite.
+fn rewrite(path: &Path, bytes: &[u8]) {
+  let before = fs::metadata(path)
+    .and_then(|metadata| metadata.modified())
+    .ok();
+  loop {
+    fs::write(path, bytes).unwrap();
+    if fs::metadata(path).unwrap().modified().ok() != before {
+      return;
+    }
+    std::thread::sleep(std::time::Duration::from_millis(10));
+  }
+}
+
+#[async_std::test]
+async fn gtfs_is_only_parsed_again_when_it_changes() {
+  let path = std::env::temp_dir().join(format!("rit_gtfsrt-reload-{}.zip", std::process::id()));
+  // Zip entries are stamped with when they were written, so reuse the bytes
+  let zip = fixture_zip();
+  rewrite(&path, &zip);
+  let source = GtfsSource::Path(path.clone());
+  let first = load_gtfs(&source, None).await.unwrap();
+
+  // Same modification time, so the file isn't even read
+  let unchanged = load_gtfs(&source, Some(&first)).await.unwrap();
+  assert!(Arc::ptr_eq(&first.gtfs, &unchanged.gtfs));
+
+  // Touched, but with the same contents
+  rewrite(&path, &zip);
+  let touched = load_gtfs(&source, Some(&unchanged)).await.unwrap();
+  assert!(Arc::ptr_eq(&first.gtfs, &touched.gtfs));
+
+  rewrite(&path, &fixture_zip_without(&["frequencies.txt"]));
+  let changed = load_gtfs(&source, Some(&touched)).await.unwrap();
+  assert!(!Arc::ptr_eq(&first.gtfs, &changed.gtfs));
+  assert!(changed.gtfs.frequencies.is_empty());
+
+  fs::remove_file(path).unwrap();
+}
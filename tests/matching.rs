@@ -0,0 +1,772 @@
+mod common;
+
+use chrono_tz::America::New_York;
+use common::{transloc, T0};
+use gtfs_rt::{
+  trip_update::StopTimeEvent, vehicle_position::VehicleStopStatus, FeedEntity, FeedMessage,
+  VehiclePosition,
+};
+use rit_gtfsrt::arrivals::get_trip_arrivals;
+use rit_gtfsrt::protobuf_route::{apply_time_profile, TimeProfile};
+use rit_gtfsrt::schedule::{
+  service_times, MatchFailure, MatchReason, RouteOutput, Schedule, ScheduleCache, StopOutput,
+  VehicleStatuses,
+};
+use rit_gtfsrt::tracker::VehicleTracker;
+use rit_gtfsrt::uncertainty::PredictionHistory;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+fn schedule_with(vehicle_statuses: VehicleStatuses) -> Schedule {
+  common::schedule_with(vehicle_statuses, Default::default())
+}
+
+/// The fixture vehicle statuses with the Campus Loop bus (6003)'s arrivals
+/// replaced by `arrivals`, as (stop code, seconds after T0)
+fn campus_loop_statuses(arrivals: &[(u64, i64)]) -> Value {
+  let mut statuses: Value = transloc("vehicle_statuses.json");
+  let fixture_arrivals = statuses["arrivals"].as_array_mut().unwrap();
+  fixture_arrivals.retain(|arrival| arrival["vehicle_id"] != 6003);
+  for &(stop, offset) in arrivals {
+    fixture_arrivals.push(json!({
+      "agency_id": 643,
+      "call_name": "603",
+      "distance": 500.0,
+      "headsign": "Campus Loop",
+      "route_id": 4000101,
+      "stop_id": 4100000 + stop,
+      "timestamp": T0 + offset,
+      "trip_id": null,
+      "type": "vehicle-based",
+      "vehicle_id": 6003,
+    }));
+  }
+  statuses
+}
+
+fn campus_loop(arrivals: &[(u64, i64)]) -> Schedule {
+  schedule_with(serde_json::from_value(campus_loop_statuses(arrivals)).unwrap())
+}
+
+/// (start_time, stop_sequence, scheduled arrival) of each of 6003's arrivals
+fn campus_loop_matches(schedule: &Schedule) -> Vec<Option<(String, u32, u64)>> {
+  schedule
+    .arrivals
+    .iter()
+    .filter(|arrival| arrival.vehicle_id == 6003)
+    .map(|arrival| {
+      let data = schedule.find_trip_id(arrival)?;
+      assert_eq!(data.trip_descriptor.trip_id.as_deref(), Some("1001"));
+      Some((
+        data.trip_descriptor.start_time?,
+        data.stop_time.stop_sequence,
+        data.scheduled_arrival,
+      ))
+    })
+    .collect()
+}
+
+/// The entities `schedule` publishes, as a fresh poller would build them
+fn feed(schedule: &Schedule) -> Vec<FeedEntity> {
+  let mut tracker = VehicleTracker::default();
+  let matched = tracker.match_arrivals(schedule, T0);
+  get_trip_arrivals(schedule, matched, &tracker, &PredictionHistory::default())
+}
+
+fn hms(h: u64, m: u64, s: u64) -> u64 {
+  h * 3600 + m * 60 + s
+}
+
+#[test]
+fn every_fixture_arrival_matches() {
+  let schedule = schedule_with(transloc::<VehicleStatuses>("vehicle_statuses.json"));
+  for arrival in &schedule.arrivals {
+    assert!(
+      schedule.find_trip_id(arrival).is_some(),
+      "{arrival:?} didn't match"
+    );
+  }
+}
+
+#[test]
+fn frequency_trip_matches_the_arrivals_stop() {
+  // Stop 2 is 3 minutes into the loop, so 08:05:30 is the 08:00 run, late
+  let schedule = campus_loop(&[(2, 330)]);
+  assert_eq!(
+    campus_loop_matches(&schedule),
+    vec![Some(("08:00:00".to_owned(), 2, hms(8, 3, 0)))]
+  );
+}
+
+#[test]
+fn frequency_trip_picks_the_nearest_run() {
+  // 08:22 at stop 2 is the 08:20 run (08:23), not the 08:10 one (08:13)
+  let schedule = campus_loop(&[(2, 22 * 60)]);
+  assert_eq!(
+    campus_loop_matches(&schedule),
+    vec![Some(("08:20:00".to_owned(), 2, hms(8, 23, 0)))]
+  );
+}
+
+#[test]
+fn frequency_trip_runs_in_every_headway_window() {
+  // Every 10 minutes until 16:00, so 15:55 at stop 2 is the 15:50 run
+  let schedule = campus_loop(&[(2, 7 * 3600 + 55 * 60)]);
+  assert_eq!(
+    campus_loop_matches(&schedule),
+    vec![Some(("15:50:00".to_owned(), 2, hms(15, 53, 0)))]
+  );
+  // Every 30 minutes after, so 16:40 is the 16:30 run, not a 16:40 one
+  let schedule = campus_loop(&[(2, 8 * 3600 + 40 * 60)]);
+  assert_eq!(
+    campus_loop_matches(&schedule),
+    vec![Some(("16:30:00".to_owned(), 2, hms(16, 33, 0)))]
+  );
+}
+
+#[test]
+fn loop_start_and_end_are_told_apart() {
+  // Heading out: stop 1 then stop 2 is the start of the 08:00 run
+  let schedule = campus_loop(&[(1, 150), (2, 330)]);
+  assert_eq!(
+    campus_loop_matches(&schedule),
+    vec![
+      Some(("08:00:00".to_owned(), 1, hms(8, 0, 0))),
+      Some(("08:00:00".to_owned(), 2, hms(8, 3, 0))),
+    ]
+  );
+
+  // Coming back: stop 4 then stop 1 is the end of the 08:00 run
+  let schedule = campus_loop(&[(4, 9 * 60 + 30), (1, 12 * 60 + 30)]);
+  assert_eq!(
+    campus_loop_matches(&schedule),
+    vec![
+      Some(("08:00:00".to_owned(), 4, hms(8, 9, 0))),
+      Some(("08:00:00".to_owned(), 5, hms(8, 12, 0))),
+    ]
+  );
+}
+
+#[test]
+fn loop_arrivals_share_one_trip_update() {
+  let schedule = campus_loop(&[(1, 150), (2, 330)]);
+  let entities = feed(&schedule);
+  let updates: Vec<_> = entities
+    .iter()
+    .filter_map(|entity| entity.trip_update.as_ref())
+    .filter(|update| update.trip.trip_id.as_deref() == Some("1001"))
+    .collect();
+  assert_eq!(updates.len(), 1);
+  let sequences: Vec<_> = updates[0]
+    .stop_time_update
+    .iter()
+    .map(|update| update.stop_sequence)
+    .collect();
+  assert_eq!(sequences, vec![Some(1), Some(2)]);
+}
+
+#[test]
+fn bunched_vehicles_share_one_trip_update() {
+  // 6005 is right ahead of 6003 on the same 08:00 run
+  let mut statuses = campus_loop_statuses(&[(1, 150), (2, 330)]);
+  for (stop, offset) in [(3, 6 * 60 + 30), (4, 9 * 60 + 30)] {
+    let mut arrival = statuses["arrivals"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .find(|arrival| arrival["vehicle_id"] == 6003)
+      .unwrap()
+      .clone();
+    arrival["vehicle_id"] = json!(6005);
+    arrival["stop_id"] = json!(4100000 + stop);
+    arrival["timestamp"] = json!(T0 + offset);
+    statuses["arrivals"].as_array_mut().unwrap().push(arrival);
+  }
+  let vehicles = statuses["vehicles"].as_array_mut().unwrap();
+  let mut vehicle = vehicles
+    .iter()
+    .find(|vehicle| vehicle["id"] == 6003)
+    .unwrap()
+    .clone();
+  vehicle["id"] = json!(6005);
+  vehicle["call_name"] = json!("605");
+  vehicle["next_stop"] = json!(4100003);
+  vehicles.push(vehicle);
+  let entities = feed(&schedule_with(serde_json::from_value(statuses).unwrap()));
+  let updates: Vec<_> = entities
+    .iter()
+    .filter(|entity| {
+      entity.trip_update.as_ref().map_or(false, |update| {
+        update.trip.trip_id.as_deref() == Some("1001")
+      })
+    })
+    .collect();
+  assert_eq!(updates.len(), 1);
+  assert_eq!(updates[0].id, "1001-20230912-08:00:00");
+  // The one further along is the one running it
+  let sequences: Vec<_> = updates[0]
+    .trip_update
+    .as_ref()
+    .unwrap()
+    .stop_time_update
+    .iter()
+    .map(|update| update.stop_sequence)
+    .collect();
+  assert_eq!(sequences, vec![Some(3), Some(4)]);
+  let update = updates[0].trip_update.as_ref().unwrap();
+  let vehicle_id = update
+    .vehicle
+    .as_ref()
+    .and_then(|vehicle| vehicle.id.as_deref());
+  assert_eq!(vehicle_id, Some("6005"));
+
+  // Only the one running it says so in its position
+  let trip = |id: &str| {
+    let entity = entities.iter().find(|entity| entity.id == id).unwrap();
+    entity.vehicle.as_ref().unwrap().trip.clone().unwrap()
+  };
+  assert_eq!(trip("vehicle-6005").start_time.as_deref(), Some("08:00:00"));
+  let behind = trip("vehicle-6003");
+  assert_eq!(behind.trip_id, None);
+  assert_eq!(behind.route_id.as_deref(), Some("101"));
+}
+
+#[test]
+fn tracker_keeps_a_vehicle_on_its_trip() {
+  // Halfway between the 08:00 and 08:10 runs, so too close to call alone
+  let ambiguous = campus_loop(&[(2, 8 * 60)]);
+  assert_eq!(campus_loop_matches(&ambiguous), vec![None]);
+
+  let mut tracker = VehicleTracker::default();
+  tracker.match_arrivals(&campus_loop(&[(1, 150), (2, 330)]), T0);
+  let assignment = tracker.get(6003).unwrap();
+  assert_eq!(assignment.trip.start_time.as_deref(), Some("08:00:00"));
+  assert_eq!(assignment.stop_sequence, 1);
+
+  let matched = tracker.match_arrivals(&ambiguous, T0 + 60);
+  let matched: Vec<_> = matched
+    .iter()
+    .filter(|data| data.arrival.vehicle_id == 6003)
+    .map(|data| data.trip_descriptor.start_time.as_deref())
+    .collect();
+  assert_eq!(matched, vec![Some("08:00:00")]);
+  assert_eq!(tracker.get(6003).unwrap().stop_sequence, 2);
+}
+
+/// The fixture vehicle statuses with Park Point bus 6001's arrivals replaced
+/// by `arrivals`, as (stop code, seconds after T0)
+fn park_point(arrivals: &[(u64, i64)]) -> Schedule {
+  let mut statuses: Value = transloc("vehicle_statuses.json");
+  let fixture_arrivals = statuses["arrivals"].as_array_mut().unwrap();
+  fixture_arrivals.retain(|arrival| arrival["vehicle_id"] != 6001);
+  for &(stop, offset) in arrivals {
+    fixture_arrivals.push(json!({
+      "agency_id": 643,
+      "call_name": "601",
+      "distance": 500.0,
+      "headsign": "Park Point",
+      "route_id": 4000102,
+      "stop_id": 4100000 + stop,
+      "timestamp": T0 + offset,
+      "trip_id": null,
+      "type": "vehicle-based",
+      "vehicle_id": 6001,
+    }));
+  }
+  schedule_with(serde_json::from_value(statuses).unwrap())
+}
+
+#[test]
+fn tracker_keeps_a_late_vehicle_on_its_trip() {
+  // 3 minutes behind the 07:40 run at stops 5 and 6
+  let mut tracker = VehicleTracker::default();
+  tracker.match_arrivals(&park_point(&[(5, -13 * 60), (6, -9 * 60)]), T0 - 16 * 60);
+  assert_eq!(
+    tracker.get(6001).unwrap().trip.trip_id.as_deref(),
+    Some("1006")
+  );
+
+  // 12 minutes behind by stops 7 and 8, which is closer to the 07:50 run's
+  // times but still only partway through its own
+  let matched = tracker.match_arrivals(&park_point(&[(7, 4 * 60), (8, 8 * 60)]), T0);
+  let matched: Vec<_> = matched
+    .iter()
+    .filter(|data| data.arrival.vehicle_id == 6001)
+    .map(|data| {
+      (
+        data.trip_descriptor.trip_id.as_deref(),
+        data.stop_time.stop_sequence,
+      )
+    })
+    .collect();
+  assert_eq!(matched, vec![(Some("1006"), 4), (Some("1006"), 5)]);
+}
+
+#[test]
+fn hinted_frequency_trips_still_need_a_clear_run() {
+  // TransLoc naming the trip says nothing about which run of it this is
+  let hinted = |arrivals: &[(u64, i64)]| {
+    let mut statuses = campus_loop_statuses(arrivals);
+    for arrival in statuses["arrivals"].as_array_mut().unwrap() {
+      if arrival["vehicle_id"] == 6003 {
+        arrival["trip_id"] = json!(1001);
+      }
+    }
+    schedule_with(serde_json::from_value(statuses).unwrap())
+  };
+  let ambiguous = hinted(&[(2, 8 * 60)]);
+  assert_eq!(
+    campus_loop_outcome(&ambiguous),
+    Err(MatchFailure::Ambiguous)
+  );
+
+  let mut tracker = VehicleTracker::default();
+  tracker.match_arrivals(&hinted(&[(1, 150), (2, 330)]), T0);
+  assert_eq!(
+    tracker.get(6003).unwrap().trip.start_time.as_deref(),
+    Some("08:00:00")
+  );
+  let matched = tracker.match_arrivals(&ambiguous, T0 + 60);
+  let matched: Vec<_> = matched
+    .iter()
+    .filter(|data| data.arrival.vehicle_id == 6003)
+    .map(|data| data.trip_descriptor.start_time.as_deref())
+    .collect();
+  assert_eq!(matched, vec![Some("08:00:00")]);
+}
+
+/// The trip Park Point bus 6001 is matched to when arriving at stop 1 at
+/// 08:00:30, where the 08:00 outbound trip starts and the 08:01 inbound one
+/// ends
+fn park_point_at_stop_1(headsign: Option<&str>, trip_id: Option<u64>) -> Option<String> {
+  park_point_at_stop_1_on(headsign, trip_id, |_| {})
+}
+
+/// `park_point_at_stop_1`, after `adjust` has had a go at 6001's status
+fn park_point_at_stop_1_on(
+  headsign: Option<&str>,
+  trip_id: Option<u64>,
+  adjust: impl Fn(&mut Value),
+) -> Option<String> {
+  let mut statuses: Value = transloc("vehicle_statuses.json");
+  let vehicle = statuses["vehicles"]
+    .as_array_mut()
+    .unwrap()
+    .iter_mut()
+    .find(|vehicle| vehicle["id"] == 6001)
+    .unwrap();
+  adjust(vehicle);
+  let arrivals = statuses["arrivals"].as_array_mut().unwrap();
+  arrivals.retain(|arrival| arrival["vehicle_id"] != 6001);
+  arrivals.push(json!({
+    "agency_id": 643,
+    "call_name": "601",
+    "distance": 300.0,
+    "headsign": headsign,
+    "route_id": 4000102,
+    "stop_id": 4100001,
+    "timestamp": T0 + 30,
+    "trip_id": trip_id,
+    "type": "vehicle-based",
+    "vehicle_id": 6001,
+  }));
+  let schedule = schedule_with(serde_json::from_value(statuses).unwrap());
+  let arrival = schedule
+    .arrivals
+    .iter()
+    .find(|arrival| arrival.vehicle_id == 6001)
+    .unwrap();
+  schedule.find_trip_id(arrival)?.trip_descriptor.trip_id
+}
+
+#[test]
+fn headsign_picks_the_direction() {
+  assert_eq!(park_point_at_stop_1(None, None), None);
+  assert_eq!(
+    park_point_at_stop_1(Some("Park Point"), None).as_deref(),
+    Some("1008")
+  );
+  assert_eq!(
+    park_point_at_stop_1(Some("Gleason Circle"), None).as_deref(),
+    Some("1118")
+  );
+}
+
+#[test]
+fn transloc_trip_id_is_used_when_it_is_a_gtfs_trip() {
+  assert_eq!(
+    park_point_at_stop_1(None, Some(1118)).as_deref(),
+    Some("1118")
+  );
+  // However late it's running: the 07:40 run left stop 1 20 minutes ago
+  assert_eq!(
+    park_point_at_stop_1(None, Some(1006)).as_deref(),
+    Some("1006")
+  );
+  // Not a GTFS trip, so it's no help
+  assert_eq!(park_point_at_stop_1(None, Some(999_999)), None);
+
+  // 1118 is a Park Point trip, so a Campus Loop bus at the stop they share
+  // isn't on it
+  let mut statuses = campus_loop_statuses(&[(1, 30), (2, 210)]);
+  let arrivals = statuses["arrivals"].as_array_mut().unwrap();
+  let at_stop_1 = arrivals
+    .iter_mut()
+    .find(|arrival| arrival["vehicle_id"] == 6003 && arrival["stop_id"] == 4100001)
+    .unwrap();
+  at_stop_1["trip_id"] = json!(1118);
+  let schedule = schedule_with(serde_json::from_value(statuses).unwrap());
+  let arrival = schedule
+    .arrivals
+    .iter()
+    .find(|arrival| arrival.vehicle_id == 6003 && arrival.stop_id == 4100001)
+    .unwrap();
+  let trip = schedule
+    .find_trip_id(arrival)
+    .map(|data| data.trip_descriptor);
+  assert_eq!(
+    trip.as_ref().and_then(|trip| trip.route_id.as_deref()),
+    Some("101")
+  );
+}
+
+#[test]
+fn vehicle_trip_id_only_covers_its_next_stop() {
+  let on_trip = |next_stop: u64| {
+    park_point_at_stop_1_on(None, None, |vehicle| {
+      vehicle["trip_id"] = json!(1006);
+      vehicle["next_stop"] = json!(next_stop);
+    })
+  };
+  assert_eq!(on_trip(4100001).as_deref(), Some("1006"));
+  // Stop 1 is past its next stop, so it could be on its next trip by then
+  assert_eq!(on_trip(4100005), None);
+}
+
+/// Where matching 6003's only arrival stopped, or how it picked a trip
+fn campus_loop_outcome(schedule: &Schedule) -> Result<MatchReason, MatchFailure> {
+  let arrival = schedule
+    .arrivals
+    .iter()
+    .find(|arrival| arrival.vehicle_id == 6003)
+    .unwrap();
+  let trace = schedule.explain_arrival(arrival, None, None);
+  trace.outcome.map(|(_, reason)| reason)
+}
+
+#[test]
+fn traces_say_where_matching_stopped() {
+  assert_eq!(
+    campus_loop_outcome(&campus_loop(&[(2, 330)])),
+    Ok(MatchReason::BestScore)
+  );
+  // Stop 6 isn't on the Campus Loop
+  assert_eq!(
+    campus_loop_outcome(&campus_loop(&[(6, 330)])),
+    Err(MatchFailure::Stop)
+  );
+  // Long after the last run
+  assert_eq!(
+    campus_loop_outcome(&campus_loop(&[(2, 20 * 3600)])),
+    Err(MatchFailure::NoTripInWindow)
+  );
+  assert_eq!(
+    campus_loop_outcome(&campus_loop(&[(2, 8 * 60)])),
+    Err(MatchFailure::Ambiguous)
+  );
+
+  let mut statuses: Value = transloc("vehicle_statuses.json");
+  for arrival in statuses["arrivals"].as_array_mut().unwrap() {
+    if arrival["vehicle_id"] == 6003 {
+      arrival["route_id"] = json!(4000199);
+    }
+  }
+  let schedule = schedule_with(serde_json::from_value(statuses).unwrap());
+  assert_eq!(campus_loop_outcome(&schedule), Err(MatchFailure::Route));
+}
+
+#[test]
+fn every_vehicle_gets_a_position() {
+  // 6003's only arrival is at a stop the Campus Loop doesn't serve
+  let schedule = campus_loop(&[(6, 330)]);
+  let entities = feed(&schedule);
+  let positions: Vec<_> = entities
+    .iter()
+    .filter(|entity| entity.vehicle.is_some())
+    .map(|entity| entity.id.as_str())
+    .collect();
+  assert_eq!(
+    positions,
+    vec![
+      "vehicle-6001",
+      "vehicle-6002",
+      "vehicle-6003",
+      "vehicle-6004"
+    ]
+  );
+
+  let campus_loop = entities
+    .iter()
+    .find(|entity| entity.id == "vehicle-6003")
+    .and_then(|entity| entity.vehicle.as_ref())
+    .unwrap();
+  let trip = campus_loop.trip.as_ref().unwrap();
+  assert_eq!(trip.route_id.as_deref(), Some("101"));
+  assert_eq!(trip.trip_id, None);
+  // It's still headed to stop 1, just not on any trip we know of
+  assert_eq!(campus_loop.stop_id.as_deref(), Some("1"));
+  assert_eq!(campus_loop.current_stop_sequence, None);
+}
+
+/// 6003's position on the Campus Loop, headed from stop 1 to stop 2, after
+/// `adjust` has had a go at its vehicle and arrivals
+fn campus_loop_position(adjust: impl Fn(&mut Value, &mut Vec<Value>)) -> VehiclePosition {
+  let mut statuses = campus_loop_statuses(&[(1, 150), (2, 330)]);
+  let mut arrivals = statuses["arrivals"].as_array().unwrap().clone();
+  let vehicle = statuses["vehicles"]
+    .as_array_mut()
+    .unwrap()
+    .iter_mut()
+    .find(|vehicle| vehicle["id"] == 6003)
+    .unwrap();
+  adjust(vehicle, &mut arrivals);
+  statuses["arrivals"] = Value::Array(arrivals);
+  let schedule = schedule_with(serde_json::from_value(statuses).unwrap());
+  feed(&schedule)
+    .into_iter()
+    .find(|entity| entity.id == "vehicle-6003")
+    .and_then(|entity| entity.vehicle)
+    .unwrap()
+}
+
+/// (status, stop_id, current_stop_sequence) of a position
+fn stop_status(
+  position: &VehiclePosition,
+) -> (Option<VehicleStopStatus>, Option<&str>, Option<u32>) {
+  (
+    position
+      .current_status
+      .and_then(VehicleStopStatus::from_i32),
+    position.stop_id.as_deref(),
+    position.current_stop_sequence,
+  )
+}
+
+#[test]
+fn vehicle_status_comes_from_its_stops() {
+  // 500m out from stop 1 at 14 mph
+  let position = campus_loop_position(|_, _| {});
+  assert_eq!(
+    stop_status(&position),
+    (Some(VehicleStopStatus::InTransitTo), Some("1"), Some(1))
+  );
+
+  let position = campus_loop_position(|_, arrivals| {
+    for arrival in arrivals
+      .iter_mut()
+      .filter(|arrival| arrival["vehicle_id"] == 6003)
+    {
+      arrival["distance"] = json!(120.0);
+    }
+  });
+  assert_eq!(
+    stop_status(&position),
+    (Some(VehicleStopStatus::IncomingAt), Some("1"), Some(1))
+  );
+
+  let position = campus_loop_position(|vehicle, _| {
+    vehicle["current_stop_id"] = json!(4100001);
+    vehicle["next_stop"] = json!(4100002);
+    vehicle["speed"] = json!(0.0);
+  });
+  assert_eq!(
+    stop_status(&position),
+    (Some(VehicleStopStatus::StoppedAt), Some("1"), Some(1))
+  );
+
+  // Pulling away from stop 1 means it's on its way to stop 2
+  let position = campus_loop_position(|vehicle, arrivals| {
+    arrivals.retain(|arrival| arrival["stop_id"] != 4100001);
+    vehicle["current_stop_id"] = json!(4100001);
+    vehicle["next_stop"] = json!(4100002);
+    vehicle["speed"] = json!(12.0);
+  });
+  assert_eq!(
+    stop_status(&position),
+    (Some(VehicleStopStatus::InTransitTo), Some("2"), Some(2))
+  );
+}
+
+#[test]
+fn inconsistent_transloc_responses_are_skipped() {
+  let mut routes: Value = transloc("routes.json");
+  let extra = json!({"id": 4000199, "long_name": "Nowhere", "short_name": "9"});
+  let mut extra_route = routes["routes"][0].clone();
+  extra_route
+    .as_object_mut()
+    .unwrap()
+    .extend(extra.as_object().unwrap().clone());
+  routes["routes"].as_array_mut().unwrap().push(extra_route);
+
+  let mut statuses = campus_loop_statuses(&[(2, 330)]);
+  for arrival in statuses["arrivals"].as_array_mut().unwrap() {
+    if arrival["vehicle_id"] == 6003 {
+      arrival["timestamp"] = json!(i64::MAX);
+    }
+  }
+
+  let gtfs = common::fixture_gtfs();
+  let settings = common::match_settings(&gtfs);
+  let schedule = Schedule::new(
+    Arc::new(gtfs),
+    transloc::<StopOutput>("stops.json"),
+    serde_json::from_value::<RouteOutput>(routes).unwrap(),
+    serde_json::from_value(statuses).unwrap(),
+    settings,
+    &mut ScheduleCache::default(),
+  );
+  assert_eq!(
+    campus_loop_outcome(&schedule),
+    Err(MatchFailure::NoTripInWindow)
+  );
+}
+
+/// The fixture vehicle statuses with the Province bus (6002) headed for
+/// stop 10 at `timestamp`
+fn province_statuses(timestamp: i64) -> VehicleStatuses {
+  let mut statuses: Value = transloc("vehicle_statuses.json");
+  let arrivals = statuses["arrivals"].as_array_mut().unwrap();
+  arrivals.retain(|arrival| arrival["vehicle_id"] != 6002);
+  arrivals.push(json!({
+    "agency_id": 643,
+    "call_name": "602",
+    "distance": 500.0,
+    "headsign": "Gleason Circle",
+    "route_id": 4000103,
+    "stop_id": 4100010,
+    "timestamp": timestamp,
+    "trip_id": null,
+    "type": "vehicle-based",
+    "vehicle_id": 6002,
+  }));
+  serde_json::from_value(statuses).unwrap()
+}
+
+/// (trip_id, start_date) a Province bus arriving at stop 10 at 01:10 on
+/// Wednesday 2023-09-13 is matched to, with the given service day cutoff
+fn province_after_midnight(cutoff: u32) -> Option<(String, String)> {
+  let statuses = province_statuses(T0 + 17 * 3600 + 10 * 60);
+  let schedule =
+    common::schedule_with_settings(statuses, |settings| settings.service_day_cutoff = cutoff);
+  let arrival = schedule
+    .arrivals
+    .iter()
+    .find(|arrival| arrival.vehicle_id == 6002)
+    .unwrap();
+  let trip = schedule.find_trip_id(arrival)?.trip_descriptor;
+  Some((trip.trip_id?, trip.start_date?))
+}
+
+#[test]
+fn after_midnight_arrivals_belong_to_the_previous_service_day() {
+  // Trip 1383 reaches stop 10 at 25:10:00 on Tuesday's service day
+  assert_eq!(
+    province_after_midnight(4),
+    Some(("1383".to_owned(), "20230912".to_owned()))
+  );
+  // With no cutoff, 01:10 is only ever Wednesday's, which has nothing then
+  assert_eq!(province_after_midnight(0), None);
+}
+
+#[test]
+fn service_days_start_at_noon_minus_12h_across_dst() {
+  // 2023-11-05 01:10 EDT, the first of the two 01:10s as clocks fall back
+  let first = 1699161000;
+  let days = service_times(first, New_York, 4);
+  assert_eq!(days.len(), 2);
+  assert_eq!(days[0].start_date(), "20231104");
+  // Saturday's service day started at 00:00 EDT, so this is 25:10:00
+  assert_eq!(days[0].secs, hms(25, 10, 0));
+  // Sunday's started at noon EST minus 12h, which is 01:00 EDT, not midnight
+  assert_eq!(days[1].start_date(), "20231105");
+  assert_eq!(days[1].secs, hms(0, 10, 0));
+
+  // The second 01:10, an hour later, is 26:10:00 into Saturday
+  let second = service_times(first + 3600, New_York, 4);
+  assert_eq!(second[0].start_date(), "20231104");
+  assert_eq!(second[0].secs, hms(26, 10, 0));
+  assert_eq!(second[1].secs, hms(1, 10, 0));
+
+  // Past the cutoff, it's only Sunday's
+  let days = service_times(first + 4 * 3600, New_York, 4);
+  assert_eq!(days.len(), 1);
+  assert_eq!(days[0].start_date(), "20231105");
+}
+
+/// The arrival event for each trip's first stop, by trip_id
+fn first_arrivals(entities: &[FeedEntity]) -> Vec<(String, StopTimeEvent)> {
+  entities
+    .iter()
+    .filter_map(|entity| entity.trip_update.as_ref())
+    .map(|update| {
+      let trip_id = update.trip.trip_id.clone().unwrap();
+      (trip_id, update.stop_time_update[0].arrival.clone().unwrap())
+    })
+    .collect()
+}
+
+fn first_arrival(entities: &[FeedEntity], trip_id: &str) -> StopTimeEvent {
+  first_arrivals(entities)
+    .into_iter()
+    .find(|(id, _)| id == trip_id)
+    .unwrap()
+    .1
+}
+
+#[test]
+fn late_arrivals_have_a_positive_delay() {
+  // 5 minutes behind trip 1383's 25:10:00 at stop 10
+  let timestamp = T0 + 17 * 3600 + 15 * 60;
+  let entities = feed(&schedule_with(province_statuses(timestamp)));
+  let arrival = first_arrival(&entities, "1383");
+  assert_eq!(arrival.delay, Some(300));
+  assert_eq!(arrival.time, Some(timestamp));
+}
+
+#[test]
+fn time_profile_picks_time_or_delay() {
+  let timestamp = T0 + 17 * 3600 + 15 * 60;
+  let entities = feed(&schedule_with(province_statuses(timestamp)));
+  let profiled = |profile| {
+    let mut message = FeedMessage {
+      header: Default::default(),
+      entity: entities.clone(),
+    };
+    apply_time_profile(&mut message, profile);
+    message.entity
+  };
+
+  let both = profiled(TimeProfile::Both);
+  assert_eq!(first_arrivals(&both), first_arrivals(&entities));
+  let province = first_arrival(&both, "1383");
+  assert_eq!(
+    (province.time, province.delay),
+    (Some(timestamp), Some(300))
+  );
+
+  let delay = profiled(TimeProfile::Delay);
+  let province = first_arrival(&delay, "1383");
+  assert_eq!((province.time, province.delay), (None, Some(300)));
+  // The Campus Loop has no schedule to be late against, so it keeps its time
+  let campus_loop = first_arrival(&delay, "1001");
+  assert!(campus_loop.time.is_some());
+  assert_eq!(campus_loop.delay, None);
+
+  let time = profiled(TimeProfile::Time);
+  for (_, arrival) in first_arrivals(&time) {
+    assert!(arrival.time.is_some());
+    assert_eq!(arrival.delay, None);
+  }
+  let province = first_arrival(&time, "1383");
+  assert_eq!(province.time, Some(timestamp));
+}
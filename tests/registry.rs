@@ -0,0 +1,43 @@
+mod common;
+
+use common::transloc;
+use rit_gtfsrt::config::Config;
+use rit_gtfsrt::registry::{Registry, TranslocAgencies};
+
+fn config() -> Config {
+  toml::from_str(
+    r#"
+    [[agencies]]
+    code = "rit"
+    [[agencies]]
+    code = "elsewhere"
+    [[agencies]]
+    id = 99
+    code = "configured"
+    "#,
+  )
+  .unwrap()
+}
+
+#[test]
+fn agencies_are_discovered_by_code() {
+  let discovered: TranslocAgencies = transloc("agencies.json");
+  let registry = Registry::build(&config(), &discovered).unwrap();
+
+  let rit = registry.get("rit").unwrap();
+  assert_eq!(rit.id, 643);
+  assert_eq!(
+    rit.name.as_deref(),
+    Some("Rochester Institute of Technology")
+  );
+  // TransLoc doesn't know it and it has no id of its own
+  assert!(registry.get("elsewhere").is_none());
+  assert_eq!(registry.get("configured").unwrap().id, 99);
+}
+
+#[test]
+fn configured_ids_work_without_discovery() {
+  let registry = Registry::build(&config(), &TranslocAgencies::default()).unwrap();
+  let codes: Vec<_> = registry.iter().map(|agency| agency.code()).collect();
+  assert_eq!(codes, vec!["configured"]);
+}
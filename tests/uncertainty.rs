@@ -0,0 +1,104 @@
+mod common;
+
+use common::T0;
+use rit_gtfsrt::schedule::Arrival;
+use rit_gtfsrt::uncertainty::PredictionHistory;
+use serde_json::json;
+
+const ROUTE: u64 = 4000101;
+const STOP: u64 = 4100002;
+
+/// 6003's prediction for the Campus Loop's stop 2
+fn arrival(timestamp: i64, distance: f64) -> Arrival {
+  serde_json::from_value(json!({
+    "agency_id": 643,
+    "call_name": "603",
+    "distance": distance,
+    "headsign": "Campus Loop",
+    "route_id": ROUTE,
+    "stop_id": STOP,
+    "timestamp": timestamp,
+    "trip_id": null,
+    "type": "vehicle-based",
+    "vehicle_id": 6003,
+  }))
+  .unwrap()
+}
+
+/// One trip to the stop starting at `start`: predicted 10 minutes out, and
+/// arriving `late` seconds after that. The second prediction is too close to
+/// arrival to count, so each run is one sample of `late / (600 + late)`.
+fn observe_run(history: &mut PredictionHistory, start: i64, late: i64) {
+  history.observe(&[arrival(start + 600, 3000.0)], start);
+  let arrived = start + 600 + late;
+  history.observe(&[arrival(arrived, 100.0)], arrived - 30);
+  history.observe(&[], arrived + 30);
+}
+
+#[test]
+fn uncertainty_has_a_floor() {
+  let history = PredictionHistory::default();
+  assert_eq!(history.uncertainty(&arrival(T0, 0.0), T0), 15);
+  // Predictions already in the past don't go below it either
+  assert_eq!(history.uncertainty(&arrival(T0 - 300, 0.0), T0), 15);
+}
+
+#[test]
+fn uncertainty_grows_with_distance_and_lookahead() {
+  let history = PredictionHistory::default();
+  let near = history.uncertainty(&arrival(T0 + 60, 200.0), T0);
+  let further = history.uncertainty(&arrival(T0 + 60, 2200.0), T0);
+  let later = history.uncertainty(&arrival(T0 + 660, 200.0), T0);
+  // 10 seconds a kilometre, and 0.15 seconds a second until there's history
+  assert_eq!(further - near, 20);
+  assert_eq!(later - near, 90);
+}
+
+#[test]
+fn error_rate_converges_on_what_is_observed() {
+  let mut history = PredictionHistory::default();
+  assert!(history.error_rate(ROUTE, STOP).is_none());
+
+  // Spot on for a while
+  for run in 0..10 {
+    observe_run(&mut history, T0 + run * 3600, 0);
+  }
+  let error_rate = history.error_rate(ROUTE, STOP).unwrap();
+  assert_eq!(error_rate.samples, 10);
+  assert_eq!(error_rate.rate, 0.0);
+
+  // Then a minute late every time
+  let late_rate = 60.0 / 660.0;
+  let mut previous = 0.0;
+  for run in 10..60 {
+    observe_run(&mut history, T0 + run * 3600, 60);
+    let rate = history.error_rate(ROUTE, STOP).unwrap().rate;
+    assert!(previous < rate && rate <= late_rate);
+    previous = rate;
+  }
+  assert!((late_rate - previous).abs() < late_rate * 0.01);
+
+  // Which shows up in the uncertainty of the next prediction
+  let learned = history.uncertainty(&arrival(T0 + 660, 0.0), T0);
+  assert_eq!(learned, (15.0 + previous * 660.0).round() as i32);
+}
+
+#[test]
+fn loop_revisits_count_as_arrivals() {
+  // TransLoc never drops the stop, it just moves on to the next lap's time
+  let mut history = PredictionHistory::default();
+  let lap = 1800;
+  history.observe(&[arrival(T0 + 600, 3000.0)], T0);
+  history.observe(&[arrival(T0 + 660, 100.0)], T0 + 630);
+  history.observe(&[arrival(T0 + 660 + lap, 6000.0)], T0 + 690);
+  let error_rate = history.error_rate(ROUTE, STOP).unwrap();
+  assert_eq!(error_rate.samples, 1);
+  assert_eq!(error_rate.rate, 60.0 / 660.0);
+
+  // Only the second lap's predictions are scored against its arrival
+  history.observe(&[arrival(T0 + 660 + lap, 100.0)], T0 + 630 + lap);
+  history.observe(&[arrival(T0 + 660 + 2 * lap, 6000.0)], T0 + 690 + lap);
+  let error_rate = history.error_rate(ROUTE, STOP).unwrap();
+  assert_eq!(error_rate.samples, 2);
+  assert!((error_rate.rate - 0.9 * 60.0 / 660.0).abs() < 1e-9);
+}